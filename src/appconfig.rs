@@ -1,13 +1,21 @@
-use std::fmt::Display;
+use std::{fmt::Display, fs};
 
 use opendal::services::S3;
 use serde::{Deserialize, Serialize};
 
+use crate::{routes::{auth::{TokenAuth, VerificationKey}, token::{AccessPolicy, TokenIssuer}}, storage::{gc::GcOptions, policy::{ImmutableTags, TagPolicy}, registry::RegistryClient, replication::{ReplicationOptions, ReplicationTarget}, upstream::Upstream}};
+
 #[derive(Serialize,Deserialize,Default,Debug)]
 #[serde(default)]
 pub struct AppConfig {
   pub server: Server,
-  pub storage: Storage
+  pub storage: Storage,
+  pub gc: Gc,
+  pub uploads: Uploads,
+  pub repositories: Vec<Repository>,
+  pub proxy: Option<Proxy>,
+  pub replication: Replication,
+  pub auth: Option<Auth>
 }
 
 
@@ -15,11 +23,12 @@ pub struct AppConfig {
 #[serde(default)]
 pub struct Server {
   pub address: String,
+  pub port: u16,
 }
 
 impl Default for Server {
     fn default() -> Self {
-        Self { address: default_ip()}
+        Self { address: default_ip(), port: 8080 }
     }
 }
 
@@ -42,7 +51,9 @@ pub enum StorageConfigError{
 
 impl Display for StorageConfigError{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f,"{}",self)
+        match self {
+          StorageConfigError::S3Error(e) => write!(f,"s3 storage: {}",e),
+        }
     }
 }
 
@@ -74,7 +85,7 @@ impl Storage {
 
         if self.local.path.is_empty() {return String::from("/tmp/.armar");}
 
-        return self.local.path.clone();
+        self.local.path.clone()
 
     }
 
@@ -94,4 +105,225 @@ pub struct S3Storage {
 #[derive(Serialize,Deserialize,Default,Debug)]
 struct Local {
   path: String
-}
\ No newline at end of file
+}
+
+/// Garbage collection of blobs and manifests no longer referenced, intervals in seconds.
+#[derive(Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Gc {
+  pub enabled: bool,
+  pub interval: u64,
+  pub grace_period: u64,
+  pub dry_run: bool,
+  pub prune_untagged: bool
+}
+
+impl Default for Gc {
+    fn default() -> Self {
+        Self { enabled: false, interval: 24 * 60 * 60, grace_period: 60 * 60, dry_run: false, prune_untagged: false }
+    }
+}
+
+impl Gc {
+
+    pub fn options(&self) -> GcOptions {
+        GcOptions { dry_run: self.dry_run, grace_period: self.grace_period, prune_untagged: self.prune_untagged }
+    }
+}
+
+/// Upload sessions: expiry of abandoned sessions in seconds (a `ttl` of 0 keeps
+/// them forever) and the chunk size advertised to clients as `OCI-Chunk-Min-Length`.
+#[derive(Serialize,Deserialize,Debug,Clone)]
+#[serde(default)]
+pub struct Uploads {
+  pub ttl: u64,
+  pub reap_interval: u64,
+  pub chunk_min_length: u64
+}
+
+impl Default for Uploads {
+    fn default() -> Self {
+        Self { ttl: 24 * 60 * 60, reap_interval: 60 * 60, chunk_min_length: 1024 * 1024 }
+    }
+}
+
+/// Settings of the repositories whose name matches the glob `name`. Tags matching
+/// one of the `immutable_tags` globs (`"*"` for every tag) or `immutable_tags_regex`
+/// can not be overwritten or deleted once pushed.
+#[derive(Serialize,Deserialize,Default,Debug)]
+#[serde(default)]
+pub struct Repository {
+  pub name: String,
+  pub immutable_tags: Vec<String>,
+  pub immutable_tags_regex: Vec<String>
+}
+
+impl Repository {
+
+    pub fn tag_policy(repositories: &[Repository]) -> Result<TagPolicy,regex::Error> {
+      let rules = repositories.iter()
+        .map(|r| ImmutableTags::new(&r.name, &r.immutable_tags, &r.immutable_tags_regex))
+        .collect::<Result<Vec<ImmutableTags>,regex::Error>>()?;
+
+      Ok(TagPolicy::new(rules))
+    }
+}
+
+/// Pull-through cache of the registry at `url`. Tags are looked up upstream again
+/// once they were served for `tag_ttl` seconds, `username` and `password` are
+/// sent when the upstream asks for credentials.
+#[derive(Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Proxy {
+  pub url: String,
+  pub username: String,
+  pub password: String,
+  pub tag_ttl: u64
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self { url: String::new(), username: String::new(), password: String::new(), tag_ttl: 5 * 60 }
+    }
+}
+
+impl Proxy {
+
+    pub fn upstream(&self) -> reqwest::Result<Upstream> {
+      let credentials = (!self.username.is_empty()).then(|| (self.username.clone(),self.password.clone()));
+
+      Upstream::new(&self.url, credentials, self.tag_ttl)
+    }
+}
+
+/// Replication of pushes and tag deletes to other registries. Queued replications
+/// are looked at every `interval` seconds and right after a push, failed ones are
+/// retried with a backoff of up to `max_backoff` seconds and given up after
+/// `max_attempts` (0 retries forever).
+#[derive(Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Replication {
+  pub interval: u64,
+  pub max_attempts: u32,
+  pub max_backoff: u64,
+  pub targets: Vec<Target>
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self { interval: 60, max_attempts: 10, max_backoff: 60 * 60, targets: Vec::new() }
+    }
+}
+
+/// Registry at `url` receiving the repositories matching one of the `repositories`
+/// globs.
+#[derive(Serialize,Deserialize,Default,Debug)]
+#[serde(default)]
+pub struct Target {
+  pub url: String,
+  pub username: String,
+  pub password: String,
+  pub repositories: Vec<String>
+}
+
+impl Replication {
+
+    pub fn options(&self) -> ReplicationOptions {
+        ReplicationOptions { max_attempts: self.max_attempts, max_backoff: self.max_backoff }
+    }
+
+    pub fn targets(&self) -> Result<Vec<ReplicationTarget>,Box<dyn std::error::Error + Send + Sync>> {
+      self.targets.iter().map(|t| {
+        let credentials = (!t.username.is_empty()).then(|| (t.username.clone(),t.password.clone()));
+        let registry = RegistryClient::new(&t.url, credentials)?;
+
+        Ok(ReplicationTarget::new(registry, &t.repositories)?)
+      }).collect()
+    }
+}
+
+/// Token authentication of every request below `/v2`. Clients are sent to
+/// `realm` for tokens issued for `service` (and by `issuer` when set), which
+/// must be signed by the PEM public key in `key` or a key of the JWKS file `jwks`.
+/// With a `signing_key` the registry issues such tokens itself at `/token`, valid
+/// for `token_ttl` seconds, to the `users` and with the access their `policies`
+/// grant.
+#[derive(Serialize,Deserialize,Debug)]
+#[serde(default)]
+pub struct Auth {
+  pub realm: String,
+  pub service: String,
+  pub issuer: String,
+  pub key: String,
+  pub jwks: String,
+  pub signing_key: String,
+  pub token_ttl: u64,
+  pub users: Vec<User>,
+  pub policies: Vec<Policy>
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self { realm: String::new(), service: String::new(), issuer: String::new(), key: String::new(), jwks: String::new(),
+          signing_key: String::new(), token_ttl: 5 * 60, users: Vec::new(), policies: Vec::new() }
+    }
+}
+
+/// A user of the token endpoint, `password_hash` is a bcrypt hash.
+#[derive(Serialize,Deserialize,Default,Debug)]
+#[serde(default)]
+pub struct User {
+  pub name: String,
+  pub password_hash: String
+}
+
+/// Grants `users` the `actions` on the repositories matching the `repositories`
+/// globs, `catalog` also grants listing the repositories.
+#[derive(Serialize,Deserialize,Default,Debug)]
+#[serde(default)]
+pub struct Policy {
+  pub users: Vec<String>,
+  pub repositories: Vec<String>,
+  pub actions: Vec<String>,
+  pub catalog: bool
+}
+
+impl Auth {
+
+    pub fn token_auth(&self) -> Result<TokenAuth,Box<dyn std::error::Error + Send + Sync>> {
+      let mut keys = Vec::new();
+      if !self.key.is_empty() {
+        keys.push(VerificationKey::from_pem(&fs::read(&self.key)?)?);
+      }
+      if !self.jwks.is_empty() {
+        keys.extend(VerificationKey::from_jwks(&fs::read_to_string(&self.jwks)?)?);
+      }
+      if keys.is_empty() {
+        return Err("auth needs a key or jwks to verify tokens with".into());
+      }
+
+      let issuer = (!self.issuer.is_empty()).then(|| self.issuer.clone());
+
+      Ok(TokenAuth::new(&self.realm, &self.service, issuer, keys))
+    }
+
+    /// The token endpoint, `None` when no `signing_key` is configured. Its tokens
+    /// must pass `token_auth`.
+    pub fn token_issuer(&self,token_auth: &TokenAuth) -> Result<Option<TokenIssuer>,Box<dyn std::error::Error + Send + Sync>> {
+      if self.signing_key.is_empty() {
+        return Ok(None);
+      }
+
+      let users = self.users.iter().map(|u| (u.name.clone(),u.password_hash.clone())).collect();
+      let policies = self.policies.iter()
+        .map(|p| AccessPolicy::new(&p.users, &p.repositories, &p.actions, p.catalog))
+        .collect::<Result<Vec<AccessPolicy>,regex::Error>>()?;
+      let issuer = if self.issuer.is_empty() { &self.service } else { &self.issuer };
+
+      let token_issuer = TokenIssuer::new(&self.service, issuer, &fs::read(&self.signing_key)?, self.token_ttl, users, policies)?;
+      token_issuer.check(token_auth)
+        .map_err(|e| format!("tokens signed with {} are rejected by the configured key or jwks: {e}",self.signing_key))?;
+
+      Ok(Some(token_issuer))
+    }
+}
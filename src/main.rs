@@ -1,12 +1,17 @@
-use std::{env::args, fs};
+use std::{env::args, fs, time::Duration};
 
-use actix_web::{get, middleware::Logger, web::{self, PayloadConfig}, App, HttpResponse, HttpServer, Responder};
+use actix_web::{get, http::StatusCode, middleware::{from_fn, ErrorHandlers, Logger}, rt, web::{self, PayloadConfig}, App, HttpResponse, HttpServer, Responder};
+use log::{error, info};
 use opendal::{services, Operator};
-use routes::{management, pull, push};
+use routes::{apierror, auth, management, pull, push, token};
+use storage::{gc::GcOptions, replication::ReplicationOptions, Storage};
 mod routes;
 mod storage;
 mod appconfig;
 
+/// Only manifests are buffered in memory, blob uploads are streamed into storage.
+const MAX_MANIFEST_SIZE: usize = 4 * 1024 * 1024;
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
 
@@ -20,45 +25,143 @@ async fn main() -> std::io::Result<()> {
     
     let config_fs = fs::read_to_string(config_path)?;
 
-    let app_cfg: appconfig::AppConfig = serde_yaml::from_str(config_fs.as_str()).unwrap();
+    let app_cfg: appconfig::AppConfig = serde_yaml::from_str(config_fs.as_str()).map_err(std::io::Error::other)?;
     
     let cache_builder = services::Fs::default().root(&app_cfg.storage.get_local());
-    let cache_op = Operator::new(cache_builder).unwrap().finish();
+    let cache_op = Operator::new(cache_builder).map_err(std::io::Error::other)?.finish();
     
     let primary_storage =  match app_cfg.storage.create_s3_op() {
     Ok(s3cfg) => {
-        Operator::new(s3cfg).unwrap().finish()        
+        Operator::new(s3cfg).map_err(std::io::Error::other)?.finish()        
     },
     Err(_) => {
         cache_op.clone()
     },
     };
 
-    let store = storage::new(primary_storage,cache_op);
+    let tag_policy = appconfig::Repository::tag_policy(&app_cfg.repositories).map_err(std::io::Error::other)?;
+    let mut store = storage::new(primary_storage,cache_op).with_tag_policy(tag_policy);
+
+    if let Some(proxy) = &app_cfg.proxy {
+        store = store.with_upstream(proxy.upstream().map_err(std::io::Error::other)?);
+    }
+
+    let replication = !app_cfg.replication.targets.is_empty();
+    if replication {
+        store = store.with_replication(app_cfg.replication.targets().map_err(std::io::Error::other)?);
+    }
    
 
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    // `ferridock <config> gc [--dry-run]` runs a single collection and exits
+    if args.get(1).is_some_and(|a| a == "gc") {
+        let mut opts = app_cfg.gc.options();
+        opts.dry_run |= args.iter().any(|a| a == "--dry-run");
+
+        let report = store.garbage_collect(&opts).await.map_err(std::io::Error::other)?;
+        print!("{report}");
+        return Ok(());
+    }
+
+    // `ferridock <config> migrate` moves blobs of the old per-repository layout into the shared pool
+    if args.get(1).is_some_and(|a| a == "migrate") {
+        let migrated = store.migrate_legacy_blobs().await.map_err(std::io::Error::other)?;
+        println!("migrated {migrated} blobs");
+        return Ok(());
+    }
+
+    let (token_auth,token_issuer) = match &app_cfg.auth {
+        Some(auth) => {
+            let token_auth = auth.token_auth().map_err(std::io::Error::other)?;
+            let token_issuer = auth.token_issuer(&token_auth).map_err(std::io::Error::other)?;
+            (Some(web::Data::new(token_auth)),token_issuer.map(web::Data::new))
+        },
+        None => (None,None),
+    };
+
     let app_data = web::Data::new(store);
+    let uploads = web::Data::new(app_cfg.uploads.clone());
+
+    if app_cfg.gc.enabled {
+        spawn_gc(app_data.clone(), app_cfg.gc.options(), Duration::from_secs(app_cfg.gc.interval));
+    }
+
+    if app_cfg.uploads.ttl > 0 {
+        spawn_upload_reaper(app_data.clone(), app_cfg.uploads.ttl, Duration::from_secs(app_cfg.uploads.reap_interval));
+    }
+
+    if replication {
+        spawn_replicator(app_data.clone(), app_cfg.replication.options(), Duration::from_secs(app_cfg.replication.interval));
+    }
+
     HttpServer::new(move || {
-        App::new()
+        let mut app = App::new()
+            .wrap(ErrorHandlers::new().handler(StatusCode::PAYLOAD_TOO_LARGE, apierror::payload_too_large))
             .wrap(Logger::default())
             .app_data(app_data.clone())
-            .app_data(PayloadConfig::new(1073741824))
-            .service(get_status)
+            .app_data(uploads.clone())
+            .app_data(PayloadConfig::new(MAX_MANIFEST_SIZE));
+        if let Some(token_auth) = &token_auth {
+            app = app.app_data(token_auth.clone());
+        }
+        if let Some(token_issuer) = &token_issuer {
+            app = app.app_data(token_issuer.clone()).configure(token::config);
+        }
+
+        app.service(get_status)
             .service(
-                web::scope("/v2").service(get_status)
+                web::scope("/v2").wrap(from_fn(auth::authorize)).service(get_status)
                 .configure(push::config)
                 .configure(pull::config)
                 .configure(management::config)
+                .default_service(web::to(apierror::not_found))
             )
     })
-    .bind((app_cfg.server.address,8080))?
+    .bind((app_cfg.server.address,app_cfg.server.port))?
     .run()
     .await
     
 }
 
+fn spawn_gc(store: web::Data<Storage>,opts: GcOptions,interval: Duration) {
+    rt::spawn(async move {
+        loop {
+            rt::time::sleep(interval).await;
+            match store.garbage_collect(&opts).await {
+                Ok(report) => info!("{report}"),
+                Err(e) => error!("garbage collection failed: {e}"),
+            }
+        }
+    });
+}
+
+fn spawn_upload_reaper(store: web::Data<Storage>,ttl: u64,interval: Duration) {
+    rt::spawn(async move {
+        loop {
+            rt::time::sleep(interval).await;
+            match store.reap_upload_sessions(ttl).await {
+                Ok(0) => {},
+                Ok(n) => info!("removed {n} stale upload sessions"),
+                Err(e) => error!("removing stale upload sessions failed: {e}"),
+            }
+        }
+    });
+}
+
+fn spawn_replicator(store: web::Data<Storage>,opts: ReplicationOptions,interval: Duration) {
+    rt::spawn(async move {
+        loop {
+            match store.replicate_pending(&opts).await {
+                Ok(0) => {},
+                Ok(n) => info!("replicated {n} references"),
+                Err(e) => error!("replication failed: {e}"),
+            }
+            store.wait_for_replication(interval).await;
+        }
+    });
+}
+
 #[get("/")]
 async fn get_status() -> impl Responder {
     HttpResponse::Ok().body("true")
@@ -1,140 +1,248 @@
-
-use actix_web::{http::{header::ContentType, StatusCode}, HttpResponse, ResponseError};
-use derive_builder::Builder;
-use oci_spec::{distribution::{ErrorCode, ErrorInfoBuilder}, image::{self}};
+use actix_web::{dev::ServiceResponse, http::{header::{self, ContentType}, StatusCode}, middleware::ErrorHandlerResponse, HttpRequest, HttpResponse, ResponseError};
+use log::error;
+use serde::Serialize;
 use thiserror::Error;
 
-use crate::storage::error::StorageError;
+use crate::storage::{digest::DigestError, error::StorageError, reference::ReferenceError};
 
 
 pub type Result<T> = std::result::Result<T,ApiError>;
 
-#[derive(Builder)]
-#[builder(field(private))]
-pub struct ApiErrorResponse {
-    message: String,
-    content_type: ContentType,
-    code: u16
+/// Body of every error response, the `errors` envelope of the distribution spec.
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    errors: [ErrorInfo<'a>; 1]
 }
 
-impl Default for ApiErrorResponse {
-    fn default() -> Self {
-        Self { message: Default::default(), content_type: ContentType::plaintext(), code: Default::default() }
-    }
+#[derive(Serialize)]
+struct ErrorInfo<'a> {
+    code: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<serde_json::Value>
 }
 
 #[derive(Debug,Error)]
 pub enum ApiError{
 
-    Storage(StorageError),
+    #[error("storage error: {0}")]
+    Storage(Box<StorageError>),
 
-    InvalidManifestFormat(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+
+    #[error("blob unknown to registry")]
+    BlobUnknown,
+
+    #[error("blob upload invalid: {0}")]
+    BlobUploadInvalid(String),
+
+    #[error("blob upload unknown to registry")]
+    BlobUploadUnknown,
 
-    ContentNotFound{kind: image::MediaType, mesg: String },
+    #[error("chunk does not continue the upload, {size} bytes are staged")]
+    UploadRangeInvalid{location: String, size: u64},
 
+    #[error("range is not satisfiable")]
     RangeIsNotStatisfied,
 
-    BlobUploadUnknown
+    #[error("provided digest did not match uploaded content")]
+    DigestInvalid,
+
+    #[error("manifest references unknown blobs: {}", .0.join(", "))]
+    ManifestBlobUnknown(Vec<String>),
+
+    #[error("manifest invalid: {0}")]
+    InvalidManifestFormat(String),
+
+    #[error("manifest unknown to registry")]
+    ManifestUnknown,
+
+    #[error("manifest is stored as {0}, which the request does not accept")]
+    ManifestNotAcceptable(String),
+
+    #[error("invalid repository name {0}")]
+    NameInvalid(String),
+
+    #[error("repository {0} is not known to registry")]
+    NameUnknown(String),
+
+    #[error("provided length did not match content length")]
+    SizeInvalid,
+
+    #[error("manifest exceeds the size limit")]
+    ManifestTooLarge,
+
+    #[error("authentication required: {reason}")]
+    Unauthorized{reason: String, challenge: String},
+
+    #[error("denied: {0}")]
+    Denied(String),
+
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("too many requests, try again later")]
+    TooManyRequests,
+
+    #[error("invalid number of results requested: {0}")]
+    PaginationNumberInvalid(String)
 }
 
 impl ApiError{
 
-    fn get_status_code(&self)  -> ApiErrorResponse {
+    /// Status and error code of the response. The codes are the ones of the
+    /// distribution spec, `PAGINATION_NUMBER_INVALID` and `UNKNOWN` are taken
+    /// from the Docker registry API which the spec leaves them out of. `NOT_FOUND`
+    /// answers paths under `/v2` that are no endpoint.
+    fn get_status_code(&self) -> (StatusCode,&'static str) {
         match self {
-            ApiError::Storage(_) => {
-                ApiErrorResponseBuilder::default()
-                    .code(StatusCode::INTERNAL_SERVER_ERROR.as_u16())
-                    .content_type(ContentType::plaintext())
-                    .message("Internal Server Error".to_string())
-                    .build().unwrap()
-            },
-            ApiError::InvalidManifestFormat(s) =>{
-                let errror_json = ErrorInfoBuilder::default()
-                .code(ErrorCode::ManifestInvalid)
-                .message(s).build().unwrap();
-
-                let msg = serde_json::to_string(&errror_json).unwrap();
-
-                ApiErrorResponseBuilder::default()
-                    .code(StatusCode::NOT_FOUND.as_u16())
-                    .content_type(ContentType::json())
-                    .message(msg)
-                    .build().unwrap()
-            }
-            ApiError::ContentNotFound { kind , mesg} => {
-                
-                let mut errror_info = ErrorInfoBuilder::default()
-                .message(mesg);
-                
-                if kind.eq(&image::MediaType::ImageManifest) {
-                    errror_info = errror_info.code(ErrorCode::ManifestUnknown);
-                }else {
-                   errror_info = errror_info.code(ErrorCode::BlobUnknown);
-                }
-
-                let error_json = errror_info.build().unwrap();
-
-                let msg = serde_json::to_string(&error_json).unwrap();
-
-                ApiErrorResponseBuilder::default()
-                    .code(StatusCode::NOT_FOUND.as_u16())
-                    .content_type(ContentType::json())
-                    .message(msg)
-                    .build().unwrap()
-            },
-            ApiError::RangeIsNotStatisfied => {
-                let errror_json = ErrorInfoBuilder::default()
-                .code(ErrorCode::BlobUploadInvalid)
-                .message("Invalid blob upload").build().unwrap();
-
-                let msg = serde_json::to_string(&errror_json).unwrap();
-
-                ApiErrorResponseBuilder::default()
-                    .code(StatusCode::RANGE_NOT_SATISFIABLE.as_u16())
-                    .content_type(ContentType::json())
-                    .message(msg)
-                    .build().unwrap()
-            },
-            ApiError::BlobUploadUnknown => {
-                
-                let errror_json = ErrorInfoBuilder::default()
-                .code(ErrorCode::BlobUploadUnknown)
-                .message("Blob upload unknown").build().unwrap();
-
-                let msg = serde_json::to_string(&errror_json).unwrap();
-
-                ApiErrorResponseBuilder::default()
-                    .code(StatusCode::NOT_FOUND.as_u16())
-                    .content_type(ContentType::json())
-                    .message(msg)
-                    .build().unwrap()
-            },
+            ApiError::Storage(_) | ApiError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR,"UNKNOWN"),
+            ApiError::BlobUnknown => (StatusCode::NOT_FOUND,"BLOB_UNKNOWN"),
+            ApiError::BlobUploadInvalid(_) => (StatusCode::BAD_REQUEST,"BLOB_UPLOAD_INVALID"),
+            ApiError::BlobUploadUnknown => (StatusCode::NOT_FOUND,"BLOB_UPLOAD_UNKNOWN"),
+            ApiError::UploadRangeInvalid { .. } | ApiError::RangeIsNotStatisfied => (StatusCode::RANGE_NOT_SATISFIABLE,"BLOB_UPLOAD_INVALID"),
+            ApiError::DigestInvalid => (StatusCode::BAD_REQUEST,"DIGEST_INVALID"),
+            ApiError::ManifestBlobUnknown(_) => (StatusCode::BAD_REQUEST,"MANIFEST_BLOB_UNKNOWN"),
+            ApiError::InvalidManifestFormat(_) => (StatusCode::BAD_REQUEST,"MANIFEST_INVALID"),
+            ApiError::ManifestUnknown => (StatusCode::NOT_FOUND,"MANIFEST_UNKNOWN"),
+            ApiError::ManifestNotAcceptable(_) => (StatusCode::NOT_ACCEPTABLE,"MANIFEST_UNKNOWN"),
+            ApiError::NameInvalid(_) => (StatusCode::BAD_REQUEST,"NAME_INVALID"),
+            ApiError::NameUnknown(_) => (StatusCode::NOT_FOUND,"NAME_UNKNOWN"),
+            ApiError::SizeInvalid => (StatusCode::BAD_REQUEST,"SIZE_INVALID"),
+            ApiError::ManifestTooLarge => (StatusCode::PAYLOAD_TOO_LARGE,"SIZE_INVALID"),
+            ApiError::Unauthorized { .. } => (StatusCode::UNAUTHORIZED,"UNAUTHORIZED"),
+            ApiError::Denied(_) => (StatusCode::FORBIDDEN,"DENIED"),
+            ApiError::Unsupported(_) => (StatusCode::METHOD_NOT_ALLOWED,"UNSUPPORTED"),
+            ApiError::NotFound(_) => (StatusCode::NOT_FOUND,"NOT_FOUND"),
+            ApiError::TooManyRequests => (StatusCode::TOO_MANY_REQUESTS,"TOOMANYREQUESTS"),
+            ApiError::PaginationNumberInvalid(_) => (StatusCode::BAD_REQUEST,"PAGINATION_NUMBER_INVALID"),
+        }
+    }
+
+    fn detail(&self) -> Option<serde_json::Value> {
+        match self {
+            ApiError::ManifestBlobUnknown(missing) => Some(serde_json::json!({"missing": missing})),
+            ApiError::UploadRangeInvalid { size, .. } => Some(serde_json::json!({"size": size})),
+            _ => None,
         }
     }
 }
 
 impl ResponseError for ApiError {
-  
+
+    fn status_code(&self) -> StatusCode {
+        self.get_status_code().0
+    }
+
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
-        let ar = self.get_status_code();
+        let (status,code) = self.get_status_code();
+
+        // internal failures are logged, the client only learns that something went wrong
+        let message = match self {
+            ApiError::Storage(_) | ApiError::Internal(_) => {
+                error!("{self}");
+                "internal server error".to_string()
+            },
+            e => e.to_string(),
+        };
+
+        let body = ErrorResponse{errors: [ErrorInfo{code,message,detail: self.detail()}]};
+
+        let mut resp = HttpResponse::build(status);
+        resp.content_type(ContentType::json());
+
+        // the client resumes a refused chunk from the range that is actually staged
+        if let ApiError::UploadRangeInvalid { location, size } = self {
+            resp.insert_header(("Location",location.as_str()))
+                .insert_header(("Range",format!("0-{}",size.saturating_sub(1))));
+        }
+
+        // the challenge tells the client where to get a token and for which scope
+        if let ApiError::Unauthorized { challenge, .. } = self {
+            resp.insert_header((header::WWW_AUTHENTICATE,challenge.as_str()));
+        }
 
-        HttpResponse::build(StatusCode::from_u16(ar.code).unwrap())
-        .content_type(ar.content_type)
-        .body(ar.message)
+        resp.json(body)
     }
 }
 
+/// Replaces the plain text 413 actix answers a manifest over the payload limit
+/// with, so the client gets the `errors` envelope like for every other error.
+pub fn payload_too_large<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+
+    let (req,_) = res.into_parts();
+    let res = ServiceResponse::new(req, ApiError::ManifestTooLarge.error_response());
+
+    Ok(ErrorHandlerResponse::Response(res.map_into_right_body()))
+}
+
+/// Answers requests under `/v2` no route matches.
+pub async fn not_found(req: HttpRequest) -> Result<HttpResponse> {
+    Err(ApiError::NotFound(format!("{} {} is not an endpoint of the registry",req.method(),req.path())))
+}
+
 impl From<StorageError> for ApiError {
     fn from(value: StorageError) -> Self {
        match value {
         StorageError::RangeIsNotStatisfied => ApiError::RangeIsNotStatisfied,
-        e => ApiError::Storage(e)
+        StorageError::DigestInvalid => ApiError::DigestInvalid,
+        StorageError::Digest(e) => e.into(),
+        StorageError::SizeInvalid => ApiError::SizeInvalid,
+        StorageError::Payload(e) => ApiError::BlobUploadInvalid(e),
+        StorageError::Denied(e) => ApiError::Denied(e),
+        StorageError::ManifestInvalid(e) => ApiError::InvalidManifestFormat(e),
+        StorageError::ManifestBlobUnknown(missing) => ApiError::ManifestBlobUnknown(missing),
+        StorageError::RateLimited => ApiError::TooManyRequests,
+        e => ApiError::Storage(Box::new(e))
+        }
+    }
+}
+
+impl From<DigestError> for ApiError {
+    fn from(value: DigestError) -> Self {
+        match value {
+            DigestError::Invalid => ApiError::DigestInvalid,
+            DigestError::Unsupported(_) => ApiError::Unsupported(value.to_string()),
         }
     }
 }
 
-impl std::fmt::Display for ApiError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f,"{}",self)
+impl From<ReferenceError> for ApiError {
+    fn from(value: ReferenceError) -> Self {
+        match value {
+            ReferenceError::InvalidTag => ApiError::InvalidManifestFormat("invalid tag".to_string()),
+            ReferenceError::Digest(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{body::MessageBody, test::{call_service, init_service, read_body_json, TestRequest}, web, App};
+
+    use super::*;
+
+    #[test]
+    fn answers_unsupported_operations_with_405() {
+        let res = ApiError::Unsupported("tokens are only issued for service registry".to_string()).error_response();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[actix_web::test]
+    async fn answers_unknown_paths_in_the_envelope_only_under_v2() {
+        let app = init_service(App::new().service(web::scope("/v2").default_service(web::to(not_found)))).await;
+
+        let res = call_service(&app, TestRequest::get().uri("/v2/nothing").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], "NOT_FOUND");
+
+        let res = call_service(&app, TestRequest::get().uri("/nothing").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(res.into_body().try_into_bytes().is_ok_and(|b| b.is_empty()));
     }
 }
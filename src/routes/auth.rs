@@ -0,0 +1,301 @@
+use std::sync::LazyLock;
+
+use actix_web::{body::MessageBody, dev::{ServiceRequest, ServiceResponse}, http::{header, Method}, middleware::Next, web, Error};
+use jsonwebtoken::{decode, decode_header, errors::ErrorKind, jwk::{AlgorithmParameters, JwkSet}, Algorithm, DecodingKey, Validation};
+use qstring::QString;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::apierror::ApiError;
+
+/// An entry of the `access` claim of the Docker token spec, also what a route
+/// needs, e.g. `repository` `library/alpine` with the actions `pull` and `push`.
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
+pub struct Access {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub actions: Vec<String>,
+}
+
+impl Access {
+
+    pub fn new(kind: &str,name: &str,actions: &[&str]) -> Access {
+        Access{kind: kind.to_string(),name: name.to_string(),actions: actions.iter().map(|a| a.to_string()).collect()}
+    }
+}
+
+/// Claims of a registry token, `aud` names the service the token is meant for.
+#[derive(Debug,Serialize,Deserialize)]
+pub struct Claims {
+    #[serde(default)]
+    pub iss: String,
+    #[serde(default)]
+    pub sub: String,
+    #[serde(default)]
+    pub aud: serde_json::Value,
+    pub exp: i64,
+    #[serde(default,skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    #[serde(default,skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    #[serde(default,skip_serializing_if = "String::is_empty")]
+    pub jti: String,
+    #[serde(default)]
+    pub access: Vec<Access>,
+}
+
+impl Claims {
+
+    /// Whether the token grants every action of `required`, `*` grants all of them.
+    fn allows(&self,required: &Access) -> bool {
+        required.actions.iter().all(|action| self.access.iter()
+            .filter(|a| a.kind == required.kind && a.name == required.name)
+            .any(|a| a.actions.iter().any(|granted| granted == action || granted == "*")))
+    }
+}
+
+/// Public key tokens are verified with, `kid` selects it when the token names one.
+pub struct VerificationKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+impl VerificationKey {
+
+    /// An RSA key verifies RS256 tokens, an EC P-256 key ES256 tokens.
+    pub fn from_pem(pem: &[u8]) -> jsonwebtoken::errors::Result<VerificationKey> {
+        let (algorithm,key) = match DecodingKey::from_rsa_pem(pem) {
+            Ok(key) => (Algorithm::RS256,key),
+            Err(_) => (Algorithm::ES256,DecodingKey::from_ec_pem(pem)?),
+        };
+        Ok(VerificationKey{kid: None,algorithm,key})
+    }
+
+    /// The RSA and EC keys of a JWKS document, keys of other types are skipped.
+    pub fn from_jwks(jwks: &str) -> jsonwebtoken::errors::Result<Vec<VerificationKey>> {
+        let set: JwkSet = serde_json::from_str(jwks)?;
+
+        set.keys.iter()
+            .filter_map(|jwk| {
+                let algorithm = match jwk.algorithm {
+                    AlgorithmParameters::RSA(_) => Algorithm::RS256,
+                    AlgorithmParameters::EllipticCurve(_) => Algorithm::ES256,
+                    _ => return None,
+                };
+                Some(DecodingKey::from_jwk(jwk).map(|key| VerificationKey{kid: jwk.common.key_id.clone(),algorithm,key}))
+            })
+            .collect()
+    }
+}
+
+/// Token authentication of the Docker token spec. Clients without a token are
+/// sent to `realm` for one, tokens must be issued for `service` and signed by
+/// one of `keys`.
+pub struct TokenAuth {
+    realm: String,
+    service: String,
+    issuer: Option<String>,
+    keys: Vec<VerificationKey>,
+}
+
+impl TokenAuth {
+
+    pub fn new(realm: &str,service: &str,issuer: Option<String>,keys: Vec<VerificationKey>) -> TokenAuth {
+        TokenAuth{realm: realm.to_string(),service: service.to_string(),issuer,keys}
+    }
+
+    pub(super) fn verify(&self,token: &str) -> jsonwebtoken::errors::Result<Claims> {
+
+        let header = decode_header(token)?;
+        let mut result = Err(ErrorKind::InvalidSignature.into());
+
+        for key in self.keys.iter().filter(|k| k.algorithm == header.alg && (header.kid.is_none() || k.kid.is_none() || k.kid == header.kid)) {
+            let mut validation = Validation::new(key.algorithm);
+            validation.validate_nbf = true;
+            validation.set_audience(&[&self.service]);
+            if let Some(issuer) = &self.issuer {
+                validation.set_issuer(&[issuer]);
+            }
+
+            result = decode::<Claims>(token, &key.key, &validation).map(|data| data.claims);
+            if result.is_ok() {
+                break;
+            }
+        }
+
+        result
+    }
+
+    /// 401 telling the client where to get a token for `required`, `error` says
+    /// why a token that was sent is not good enough.
+    fn unauthorized(&self,required: &[Access],error: Option<&str>,reason: String) -> ApiError {
+
+        let mut challenge = format!("Bearer realm=\"{}\",service=\"{}\"",self.realm,self.service);
+        if !required.is_empty() {
+            let scope: Vec<String> = required.iter().map(|a| format!("{}:{}:{}",a.kind,a.name,a.actions.join(","))).collect();
+            challenge.push_str(&format!(",scope=\"{}\"",scope.join(" ")));
+        }
+        if let Some(error) = error {
+            challenge.push_str(&format!(",error=\"{error}\""));
+        }
+
+        ApiError::Unauthorized{reason,challenge}
+    }
+}
+
+type Actions = fn(&Method) -> &'static [&'static str];
+
+/// The routes of `push`, `pull` and `management` below `/v2/<name>` and the
+/// actions they need on the repository, matched in order.
+static ROUTES: LazyLock<Vec<(Regex,Actions)>> = LazyLock::new(|| {
+    let route = |pattern: &str| Regex::new(pattern).expect("route pattern is valid");
+    vec![
+        (route(r"^/v2/(.+)/blobs/uploads/[^/]*$"),|_| &["pull","push"]),
+        (route(r"^/v2/(.+)/manifests/[^/]+$"),|m| match *m {
+            Method::PUT => &["pull","push"],
+            Method::DELETE => &["delete"],
+            _ => &["pull"],
+        }),
+        (route(r"^/v2/(.+)/blobs/[^/]+$"),|m| match *m {
+            Method::DELETE => &["delete"],
+            _ => &["pull"],
+        }),
+        (route(r"^/v2/(.+)/tags/list$"),|_| &["pull"]),
+        (route(r"^/v2/(.+)/referrers/[^/]+$"),|_| &["pull"]),
+    ]
+});
+
+/// Access a request needs, nothing beyond a valid token for `/v2/` itself.
+fn required_access(req: &ServiceRequest) -> Vec<Access> {
+
+    let path = req.path();
+    if path == "/v2/_catalog" {
+        return vec![Access::new("registry", "catalog", &["*"])];
+    }
+
+    let Some((captures,actions)) = ROUTES.iter().find_map(|(route,actions)| route.captures(path).map(|c| (c,actions))) else {
+        return Vec::new();
+    };
+
+    let mut required = vec![Access::new("repository", &captures[1], actions(req.method()))];
+
+    // a cross-repository mount reads the blob from the source repository
+    if req.method() == Method::POST {
+        let q = QString::from(req.query_string());
+        if let (Some(_),Some(from)) = (q.get("mount"),q.get("from")) {
+            required.push(Access::new("repository", from, &["pull"]));
+        }
+    }
+
+    required
+}
+
+/// Middleware of the `/v2` scope. When token authentication is configured every
+/// request needs a bearer token granting the access its route needs.
+pub async fn authorize(req: ServiceRequest,next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>,Error> {
+
+    let Some(auth) = req.app_data::<web::Data<TokenAuth>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let required = required_access(&req);
+
+    let token = req.headers().get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme,_)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_,token)| token.trim());
+    let Some(token) = token else {
+        return Err(auth.unauthorized(&required, None, "no bearer token".to_string()).into());
+    };
+
+    let claims = auth.verify(token).map_err(|e| auth.unauthorized(&required, Some("invalid_token"), e.to_string()))?;
+
+    if let Some(missing) = required.iter().find(|a| !claims.allows(a)) {
+        let reason = format!("token does not grant {} on {}",missing.actions.join(","),missing.name);
+        return Err(auth.unauthorized(&required, Some("insufficient_scope"), reason).into());
+    }
+
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn required(method: Method,uri: &str) -> Vec<Access> {
+        required_access(&TestRequest::default().method(method).uri(uri).to_srv_request())
+    }
+
+    fn claims(access: Vec<Access>) -> Claims {
+        Claims{iss: String::new(),sub: String::new(),aud: serde_json::Value::Null,exp: 0,nbf: None,iat: None,jti: String::new(),access}
+    }
+
+    #[test]
+    fn maps_routes_to_repository_actions() {
+        let cases = [
+            (Method::GET,"/v2/library/alpine/manifests/latest",&["pull"][..]),
+            (Method::HEAD,"/v2/library/alpine/manifests/latest",&["pull"]),
+            (Method::PUT,"/v2/library/alpine/manifests/latest",&["pull","push"]),
+            (Method::DELETE,"/v2/library/alpine/manifests/latest",&["delete"]),
+            (Method::GET,"/v2/library/alpine/blobs/sha256:abc",&["pull"]),
+            (Method::DELETE,"/v2/library/alpine/blobs/sha256:abc",&["delete"]),
+            (Method::POST,"/v2/library/alpine/blobs/uploads/",&["pull","push"]),
+            (Method::PATCH,"/v2/library/alpine/blobs/uploads/1234",&["pull","push"]),
+            (Method::DELETE,"/v2/library/alpine/blobs/uploads/1234",&["pull","push"]),
+            (Method::GET,"/v2/library/alpine/tags/list",&["pull"]),
+            (Method::GET,"/v2/library/alpine/referrers/sha256:abc",&["pull"]),
+        ];
+
+        for (method,uri,actions) in cases {
+            assert_eq!(required(method.clone(), uri), vec![Access::new("repository", "library/alpine", actions)], "{method} {uri}");
+        }
+    }
+
+    #[test]
+    fn names_nested_repositories_like_routes() {
+        assert_eq!(required(Method::GET, "/v2/a/blobs/b/blobs/sha256:abc"), vec![Access::new("repository", "a/blobs/b", &["pull"])]);
+        assert_eq!(required(Method::GET, "/v2/a/manifests/b/tags/list"), vec![Access::new("repository", "a/manifests/b", &["pull"])]);
+    }
+
+    #[test]
+    fn requires_pull_on_the_source_of_a_mount() {
+        assert_eq!(required(Method::POST, "/v2/a/blobs/uploads/?mount=sha256:abc&from=b"), vec![
+            Access::new("repository", "a", &["pull","push"]),
+            Access::new("repository", "b", &["pull"]),
+        ]);
+        assert_eq!(required(Method::POST, "/v2/a/blobs/uploads/?from=b"), vec![Access::new("repository", "a", &["pull","push"])]);
+    }
+
+    #[test]
+    fn requires_catalog_access_for_the_catalog_only() {
+        assert_eq!(required(Method::GET, "/v2/_catalog"), vec![Access::new("registry", "catalog", &["*"])]);
+        assert_eq!(required(Method::GET, "/v2/"), Vec::new());
+    }
+
+    #[test]
+    fn grants_required_actions() {
+        let token = claims(vec![Access::new("repository", "a", &["pull"]),Access::new("repository", "a", &["push"]),Access::new("repository", "b", &["*"])]);
+
+        assert!(token.allows(&Access::new("repository", "a", &["pull","push"])));
+        assert!(token.allows(&Access::new("repository", "b", &["delete"])));
+        assert!(!token.allows(&Access::new("repository", "a", &["delete"])));
+        assert!(!token.allows(&Access::new("repository", "c", &["pull"])));
+        assert!(!token.allows(&Access::new("registry", "a", &["pull"])));
+    }
+
+    #[test]
+    fn challenges_with_the_required_scope() {
+        let auth = TokenAuth::new("https://auth.example/token", "registry", None, Vec::new());
+        let required = [Access::new("repository", "a", &["pull","push"]),Access::new("repository", "b", &["pull"])];
+
+        let ApiError::Unauthorized{challenge,..} = auth.unauthorized(&required, Some("insufficient_scope"), String::new()) else {
+            panic!("not an authentication error");
+        };
+        assert_eq!(challenge, "Bearer realm=\"https://auth.example/token\",service=\"registry\",scope=\"repository:a:pull,push repository:b:pull\",error=\"insufficient_scope\"");
+    }
+}
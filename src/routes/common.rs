@@ -0,0 +1,189 @@
+use std::{ops::Range, sync::LazyLock};
+
+use qstring::QString;
+use regex::Regex;
+
+use super::apierror::{self, ApiError};
+
+/// Repository name grammar of the distribution spec.
+static NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*(/[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*)*$").expect("repository name pattern is valid")
+});
+
+/// Refuses repository names outside the spec grammar, which also keeps them from
+/// escaping the repository's directory in storage.
+pub fn validate_name(name: &str) -> apierror::Result<()> {
+    if !NAME.is_match(name) {
+        return Err(ApiError::NameInvalid(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Outcome of matching a `Range` request header against a blob of known size.
+pub enum ByteRange {
+    /// No usable range was requested, the whole content is served.
+    Full,
+    /// A single satisfiable range, the end is exclusive.
+    Partial(Range<u64>),
+    /// The range lies outside of the content.
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range as described in RFC 9110. Multiple ranges and
+/// other units are not supported and fall back to serving the full content.
+pub fn parse_byte_range(header: Option<&str>,size: u64) -> ByteRange {
+
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let Some((start,end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let range = match (start.trim(),end.trim()) {
+        ("","") => return ByteRange::Full,
+        ("",suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => size.saturating_sub(n)..size,
+            Err(_) => return ByteRange::Full,
+        },
+        (start,"") => match start.parse::<u64>() {
+            Ok(start) => start..size,
+            Err(_) => return ByteRange::Full,
+        },
+        (start,end) => match (start.parse::<u64>(),end.parse::<u64>()) {
+            (Ok(start),Ok(end)) if start <= end => start..size.min(end.saturating_add(1)),
+            _ => return ByteRange::Full,
+        },
+    };
+
+    if range.start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Partial(range)
+}
+
+/// The `n` and `last` query parameters of a paginated listing.
+pub struct Pagination {
+    n: Option<usize>,
+    last: Option<String>,
+}
+
+impl Pagination {
+
+    pub fn from_query(q: &QString) -> apierror::Result<Pagination> {
+
+        let n = match q.get("n") {
+            Some(n) => Some(n.parse::<usize>().map_err(|_| ApiError::PaginationNumberInvalid(n.to_string()))?),
+            None => None,
+        };
+        let last = q.get("last").map(str::to_string);
+
+        Ok(Pagination{n,last})
+    }
+
+    /// Returns the entries of the sorted `items` following `last`, at most `n` of
+    /// them, and the `Link` header of the next page when entries are left over.
+    pub fn page(&self,path: &str,items: Vec<String>) -> (Vec<String>,Option<String>) {
+
+        let mut items: Vec<String> = match &self.last {
+            Some(last) => items.into_iter().filter(|i| i > last).collect(),
+            None => items,
+        };
+
+        let Some(n) = self.n else {
+            return (items,None);
+        };
+
+        if items.len() <= n || n == 0 {
+            items.truncate(n);
+            return (items,None);
+        }
+
+        items.truncate(n);
+        let link = items.last().map(|last| format!("<{path}?n={n}&last={last}>; rel=\"next\""));
+
+        (items,link)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(header: &str,size: u64) -> Option<Range<u64>> {
+        match parse_byte_range(Some(header), size) {
+            ByteRange::Partial(range) => Some(range),
+            ByteRange::Full => panic!("{header} is served in full"),
+            ByteRange::Unsatisfiable => None,
+        }
+    }
+
+    #[test]
+    fn validates_repository_names() {
+        for name in ["alpine", "library/alpine", "a/b/c", "my-app", "my__app", "v1.2/x_y"] {
+            assert!(validate_name(name).is_ok(), "{name}");
+        }
+        for name in ["", "Alpine", "a//b", "/a", "a/", "-a", "a-", "a/../b", "a___b"] {
+            assert!(validate_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(range("bytes=0-9", 100), Some(0..10));
+        assert_eq!(range("bytes=90-200", 100), Some(90..100));
+        assert_eq!(range("bytes=50-", 100), Some(50..100));
+        assert_eq!(range("bytes=-10", 100), Some(90..100));
+        assert_eq!(range("bytes=-200", 100), Some(0..100));
+        assert_eq!(range("bytes=100-", 100), None);
+        assert_eq!(range("bytes=-0", 100), None);
+    }
+
+    #[test]
+    fn saturates_the_end_of_a_byte_range() {
+        assert_eq!(range(&format!("bytes=0-{}", u64::MAX), 100), Some(0..100));
+        assert_eq!(range(&format!("bytes={}-{}", u64::MAX, u64::MAX), 100), None);
+    }
+
+    #[test]
+    fn serves_unusable_ranges_in_full() {
+        for header in [None, Some("items=0-1"), Some("bytes=0-1,5-6"), Some("bytes=5-1"), Some("bytes=a-b"), Some("bytes=-")] {
+            assert!(matches!(parse_byte_range(header, 100), ByteRange::Full), "{header:?}");
+        }
+    }
+
+    fn items(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn pages_through_listings() {
+        let first = Pagination::from_query(&QString::from("n=2")).unwrap();
+        let (page,link) = first.page("/v2/a/tags/list", items(&["a", "b", "c"]));
+        assert_eq!(page, items(&["a", "b"]));
+        assert_eq!(link.as_deref(), Some("</v2/a/tags/list?n=2&last=b>; rel=\"next\""));
+
+        let next = Pagination::from_query(&QString::from("n=2&last=b")).unwrap();
+        assert_eq!(next.page("/v2/a/tags/list", items(&["a", "b", "c"])), (items(&["c"]),None));
+
+        let all = Pagination::from_query(&QString::from("")).unwrap();
+        assert_eq!(all.page("/v2/a/tags/list", items(&["a", "b"])), (items(&["a", "b"]),None));
+
+        let none = Pagination::from_query(&QString::from("n=0")).unwrap();
+        assert_eq!(none.page("/v2/a/tags/list", items(&["a"])), (Vec::new(),None));
+    }
+
+    #[test]
+    fn rejects_invalid_page_sizes() {
+        for query in ["n=-1", "n=abc", "n="] {
+            assert!(matches!(Pagination::from_query(&QString::from(query)), Err(ApiError::PaginationNumberInvalid(_))), "{query}");
+        }
+    }
+}
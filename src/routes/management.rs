@@ -1,31 +1,53 @@
-use actix_web::{route, web::{self, Buf, Data}, HttpRequest, HttpResponse};
-use oci_spec::image::{Descriptor, ImageIndex, ImageManifest, MediaType};
+use actix_web::{route, web, HttpRequest, HttpResponse};
+use oci_spec::image::MediaType;
 use qstring::QString;
 
 
-use crate::{routes::apierror::{self, ApiError}, storage::{common::Tags, Storage}};
+use crate::{routes::{apierror::{self, ApiError}, common::{validate_name, Pagination}}, storage::{common::{Catalog, Tags}, digest::Digest, error::StorageError, reference::Reference, Storage}};
 
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg
+    .service(get_catalog)
     .service(delete_manifest)
     .service(delete_blob)
     .service(get_tags)
     .service(get_referrers);
 }
 
+#[route("/_catalog",method="GET")]
+async fn get_catalog(req: HttpRequest,store: web::Data<Storage>) -> apierror::Result<HttpResponse> {
+
+    let pagination = Pagination::from_query(&QString::from(req.query_string()))?;
+
+    let repositories = store.list_catalog().await?;
+    let (repositories,link) = pagination.page("/v2/_catalog", repositories);
+
+    let mut resp = HttpResponse::Ok();
+    if let Some(link) = link {
+        resp.insert_header(("Link",link));
+    }
+
+    Ok(resp.json(Catalog{repositories}))
+}
+
 #[route("/{rep:.*}/manifests/{ref}",method="DELETE")]
 async fn delete_manifest(info: web::Path<(String,String)>,store: web::Data<Storage>) -> apierror::Result<HttpResponse> {
     
-    let (repo,digest) = info.into_inner();
+    let (repo,reference) = info.into_inner();
+    validate_name(&repo)?;
+
+    let result = match reference.parse::<Reference>()? {
+        Reference::Tag(tag) => store.delete_tag(&repo, &tag).await,
+        Reference::Digest(digest) => store.delete_manifest(&repo, &digest).await,
+    };
 
-    if !digest.starts_with("sha256:") {
-       return  Ok(HttpResponse::MethodNotAllowed().finish());
+    match result {
+        Ok(()) => {},
+        Err(StorageError::ContenNotFound) => return Err(ApiError::ManifestUnknown),
+        Err(e) => return Err(e.into()),
     }
-    
-    let result =  store.delete_manifest(&repo, &digest).await;
-    if result.is_err() {return  Err(ApiError::ContentNotFound { kind: MediaType::ImageManifest, mesg: "manifest is unknown".to_string() });}
-    
+
     Ok(HttpResponse::Accepted().finish())
 }
 
@@ -33,8 +55,13 @@ async fn delete_manifest(info: web::Path<(String,String)>,store: web::Data<Stora
 async fn delete_blob(info: web::Path<(String,String)>,store: web::Data<Storage>) -> apierror::Result<HttpResponse> {
   
     let (repo,digest) = info.into_inner();
-    let result =  store.delete_blob(&repo, &digest).await;
-    if result.is_err() {return Err(ApiError::ContentNotFound { kind: MediaType::Other("Blob".to_string()), mesg: "blob is unknown".to_string() });}
+    validate_name(&repo)?;
+    let digest: Digest = digest.parse()?;
+    match store.delete_blob(&repo, &digest).await {
+        Ok(()) => {},
+        Err(StorageError::ContenNotFound) => return Err(ApiError::BlobUnknown),
+        Err(e) => return Err(e.into()),
+    }
   
     Ok(HttpResponse::Accepted().finish())
             
@@ -44,102 +71,48 @@ async fn delete_blob(info: web::Path<(String,String)>,store: web::Data<Storage>)
 async fn get_tags(req:HttpRequest,info: web::Path<String>,store: web::Data<Storage>) -> apierror::Result<HttpResponse> {
   
     let repo= info.into_inner();
+    validate_name(&repo)?;
 
-    let qs = req.query_string();
-    let q = QString::from(qs);
+    let pagination = Pagination::from_query(&QString::from(req.query_string()))?;
+
+    if !store.repository_exists(&repo).await? {
+        return Err(ApiError::NameUnknown(repo));
+    }
 
-    
-   let n = match q.get("n") {
-        Some(n) => {
-           let s =  n.to_string();
-           s.parse::<usize>().unwrap()
-        },
-        None => 0,
-    };
-        
     let mut tag_list = store.get_tags(&repo).await?;
-    if n!= 0&&n < tag_list.tags.len() {
-        let new_tags = tag_list.tags.split_off(n+1);
-       return   Ok(HttpResponse::Ok().json(Tags{name:tag_list.name,tags:new_tags}));
+    tag_list.tags.sort();
+
+    let (tags,link) = pagination.page(&format!("/v2/{repo}/tags/list"), tag_list.tags);
+
+    let mut resp = HttpResponse::Ok();
+    if let Some(link) = link {
+        resp.insert_header(("Link",link));
     }
 
-    Ok(HttpResponse::Ok().json(tag_list))
-    
+    Ok(resp.json(Tags{name:tag_list.name,tags}))
 }
 
-//TODO:: add tag=? query
 #[route("/{rep:.*}/referrers/{digest}",method="GET")]
 async fn get_referrers(req: HttpRequest,info: web::Path<(String,String)>,store: web::Data<Storage>) -> apierror::Result<HttpResponse> {
   
     let (repo,digest) = info.into_inner();
-   
+    validate_name(&repo)?;
+    let digest: Digest = digest.parse()?;
+
     let qs = req.query_string();
     let q = QString::from(qs);
 
-    
-   let artifact_type = match q.get("artifactType") {
-        Some(a) => a,
-        None => "",
-    };
-    
-    let mut index  =store.get_image_index(&repo).await?;
-        
-    let manifests = index.manifests();
-    let mut new_manifests:Vec<Descriptor> = Vec::new();
-
-    for m in manifests.iter() {
+    let mut index = store.get_referrers(&repo, &digest).await?;
 
-       let subject_digest = get_subject_digest(m, repo.clone(), store.clone()).await;
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(MediaType::ImageIndex.to_string());
 
-        if (!subject_digest.is_empty())&&subject_digest.eq(&digest){
-            new_manifests.push(m.clone());
-        }
+    if let Some(artifact_type) = q.get("artifactType") {
+        let mut manifests = index.manifests().to_owned();
+        manifests.retain(|m| m.artifact_type().as_ref().is_some_and(|a| a.to_string().eq(artifact_type)));
+        index.set_manifests(manifests);
+        resp.insert_header(("OCI-Filters-Applied","artifactType"));
     }
-    
-    if !artifact_type.is_empty(){
-        for (i,nm) in new_manifests.clone().iter().enumerate() {
-            match nm.artifact_type() {
-                Some(a) => {
-                    if !a.to_string().eq(artifact_type){
-                        new_manifests.remove(i);
-                    }
-                },
-                None => {},
-            }
-            }        
-    }
-    
-    index.set_manifests(new_manifests);
 
-    Ok(HttpResponse::Ok().content_type(MediaType::ImageIndex.to_string()).body(index.to_string().unwrap()))
-            
+    Ok(resp.body(index.to_string().map_err(StorageError::from)?))
 }
-
-async fn get_subject_digest(d: &Descriptor,repo: String, store: Data<Storage>) -> String {
-   
-    if d.media_type().eq(&MediaType::ImageManifest) {
-
-    let  m_data = store.get_manifest(&repo, &d.digest().to_string()).await.unwrap();
-    let manifest = ImageManifest::from_reader(m_data.reader()).expect(d.digest());
-   
-    return  match manifest.subject(){
-        Some(s) =>s.digest().to_string(),
-        None => String::new(),
-        };
-   
-    }
-    if d.media_type().eq(&MediaType::ImageIndex) {
-
-        let  m_data = store.get_manifest(&repo, &d.digest().to_string()).await.unwrap();
-        let manifest = ImageIndex::from_reader(m_data.reader()).expect(d.digest());
-        
-        return  match manifest.subject(){
-            Some(s) =>s.digest().to_string(),
-            None => String::new(),
-            };
-       
-        }
-
-    String::new()
-
-}
\ No newline at end of file
@@ -2,4 +2,6 @@ pub mod pull;
 pub mod push;
 pub mod management;
 pub mod common;
-pub mod apierror;
\ No newline at end of file
+pub mod apierror;
+pub mod auth;
+pub mod token;
\ No newline at end of file
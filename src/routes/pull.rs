@@ -1,8 +1,8 @@
-use actix_web::{http::Method, route, web::{self}, HttpMessage, HttpRequest, HttpResponse};
-use oci_spec::image::MediaType;
+use actix_web::{body::SizedStream, http::{header::{self, ContentType}, Method}, route, web::{self, Bytes}, HttpRequest, HttpResponse};
+use futures_util::stream;
 
 
-use crate::{routes::apierror::{self, ApiError}, storage::{error::StorageError, Storage}};
+use crate::{routes::{apierror::{self, ApiError}, common::{parse_byte_range, validate_name, ByteRange}}, storage::{digest::Digest, error::StorageError, reference::Reference, Storage}};
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg
@@ -13,58 +13,126 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 
 #[route("/{rep:.*}/manifests/{ref}",method="GET",method="HEAD")]
 async fn pull_manifest(req:HttpRequest,info: web::Path<(String,String)>,store: web::Data<Storage>) -> apierror::Result<HttpResponse>{
-    
-    let content_type = req.content_type();
 
-    if !content_type.eq(MediaType::ImageManifest.to_string().as_str()) && !content_type.is_empty(){
-        let er_resp = format!("the given format is not accepted for manifest {}",content_type);
-       return Err(ApiError::InvalidManifestFormat(er_resp));
-    }
-   
     let (repo,tag) = info.into_inner();
-    
-    match store.get_manifest(&repo, &tag).await {
-    Ok(file) => {
-        if req.method().eq(&Method::GET) {
-        Ok(HttpResponse::Ok().content_type(MediaType::ImageManifest.to_string()).body(file))
-        }else {
-            Ok(HttpResponse::Ok().content_type(MediaType::ImageManifest.to_string()).finish())
-        }
-    },
-    Err(e) => {
-        match e {
-            StorageError::ContenNotFound => {
-             Err(ApiError::ContentNotFound { kind: MediaType::ImageManifest, mesg: "manifest is unknown".to_string() })
-            }
-            _=>{Err(ApiError::Storage(e))}
-        }
-    },
+    validate_name(&repo)?;
+    let Ok(reference) = tag.parse::<Reference>() else {
+        return Err(ApiError::ManifestUnknown);
+    };
+
+    // HEAD is answered from the index alone
+    let result = if req.method().eq(&Method::HEAD) {
+        store.stat_manifest(&repo, &reference).await.map(|d| (d,None))
+    } else {
+        store.get_manifest(&repo, &reference).await.map(|(d,file)| (d,Some(file)))
+    };
+
+    let (descriptor,file) = match result {
+        Ok(found) => found,
+        Err(StorageError::ContenNotFound) => return Err(ApiError::ManifestUnknown),
+        Err(e) => return Err(e.into()),
+    };
+
+    let media_type = descriptor.media_type().to_string();
+    if !accepts(&req, &media_type) {
+        return Err(ApiError::ManifestNotAcceptable(media_type));
+    }
+
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(media_type)
+        .insert_header(("Docker-Content-Digest",descriptor.digest().as_str()));
+
+    match file {
+        Some(file) => Ok(resp.body(file)),
+        // the sized empty body only carries the length
+        None => {
+            let size = descriptor.size() as u64;
+            Ok(resp.no_chunking(size).body(SizedStream::new(size, stream::empty::<std::io::Result<Bytes>>())))
+        },
     }
-        
+}
+
+/// Whether the `Accept` headers of the request allow `media_type`, a request
+/// without any accepts every media type.
+fn accepts(req: &HttpRequest,media_type: &str) -> bool {
+
+    let mut accepted = req.headers().get_all(header::ACCEPT)
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(|a| a.split(';').next().unwrap_or_default().trim())
+        .filter(|a| !a.is_empty())
+        .peekable();
+
+    if accepted.peek().is_none() {
+        return true;
+    }
+
+    accepted.any(|a| a == "*/*" || a == media_type)
 }
 
 #[route("/{rep:.*}/blobs/{digest}",method="GET",method="HEAD")]
 async fn pull_blob(req:HttpRequest,info: web::Path<(String,String)>,store: web::Data<Storage>) -> apierror::Result<HttpResponse>{
-  
+
     let (repo,digest) = info.into_inner();
-    match store.get_blobs(&repo, &digest).await {
-        Ok(file) => {
-            if req.method().eq(&Method::GET) {
-                Ok(HttpResponse::Ok().body(file))
-                
-            }else {
-                Ok(HttpResponse::Ok().finish())
-            }
+    validate_name(&repo)?;
+    let digest: Digest = digest.parse()?;
+
+    let size = match store.stat_blob(&repo, &digest).await {
+        Ok(size) => size,
+        Err(StorageError::ContenNotFound) => return pull_upstream_blob(&req, &repo, &digest, store).await,
+        Err(e) => return Err(e.into()),
+    };
+
+    let range = req.headers().get(header::RANGE).and_then(|r| r.to_str().ok());
+
+    let (mut resp,range) = match parse_byte_range(range, size) {
+        ByteRange::Full => (HttpResponse::Ok(),0..size),
+        ByteRange::Partial(range) => {
+            let mut resp = HttpResponse::PartialContent();
+            resp.insert_header((header::CONTENT_RANGE,format!("bytes {}-{}/{}",range.start,range.end - 1,size)));
+            (resp,range)
         },
-        Err(e) => {
-            match e {
-                StorageError::ContenNotFound => {
-                    Err(ApiError::ContentNotFound { kind: MediaType::Other("Blob".to_string()), mesg: "blob is unknown".to_string() })
-                }
-                _=>{Err(ApiError::Storage(e))}
-            }
-        }
-        }
-            
+        ByteRange::Unsatisfiable => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header((header::CONTENT_RANGE,format!("bytes */{size}")))
+                .finish());
+        },
+    };
+
+    resp.content_type(ContentType::octet_stream())
+        .insert_header(("Docker-Content-Digest",digest.to_string()))
+        .insert_header((header::ACCEPT_RANGES,"bytes"))
+        .no_chunking(range.end - range.start);
+
+    // HEAD is answered from metadata alone, the sized empty body only carries the length
+    if req.method().eq(&Method::HEAD) {
+        return Ok(resp.body(SizedStream::new(range.end - range.start, stream::empty::<std::io::Result<Bytes>>())));
+    }
+
+    let stream = store.get_blob_stream(&repo, &digest, range).await?;
+
+    Ok(resp.streaming(stream))
+}
+
+/// Serves a blob missing here from the upstream of a pull-through cache. It is
+/// always sent in full, the content is cached while it passes through.
+async fn pull_upstream_blob(req: &HttpRequest,repo: &String,digest: &Digest,store: web::Data<Storage>) -> apierror::Result<HttpResponse> {
+
+    let not_found = |e| match e {
+        StorageError::ContenNotFound => ApiError::BlobUnknown,
+        e => e.into(),
+    };
+
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(ContentType::octet_stream())
+        .insert_header(("Docker-Content-Digest",digest.to_string()));
+
+    if req.method().eq(&Method::HEAD) {
+        let size = store.stat_upstream_blob(repo, digest).await.map_err(not_found)?;
+        return Ok(resp.no_chunking(size).body(SizedStream::new(size, stream::empty::<std::io::Result<Bytes>>())));
+    }
+
+    let (size,stream) = store.into_inner().fetch_upstream_blob(repo, digest).await.map_err(not_found)?;
 
+    Ok(resp.no_chunking(size).streaming(stream))
 }
@@ -1,7 +1,8 @@
-use actix_web::{http::header::{self, HeaderValue}, route, web::{self, Bytes}, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::{http::header::{self, HeaderValue}, route, web::{self, Bytes, Payload}, HttpMessage, HttpRequest, HttpResponse};
 use qstring::QString;
+use uuid::Uuid;
 
-use crate::{routes::apierror::{self, ApiError}, storage::{error::StorageError, Storage}};
+use crate::{appconfig::Uploads, routes::{apierror::{self, ApiError}, common::validate_name}, storage::{digest::Digest, error::StorageError, reference::Reference, Storage}};
 
  pub fn config(cfg: &mut web::ServiceConfig) {
     cfg
@@ -9,7 +10,8 @@ use crate::{routes::apierror::{self, ApiError}, storage::{error::StorageError, S
         .service(create_blob_uploads)
         .service(update_blob)
         .service(update_blob_chunks)
-        .service(get_stale_blob_upload);
+        .service(get_stale_blob_upload)
+        .service(cancel_blob_upload);
         
 }
 
@@ -17,87 +19,107 @@ use crate::{routes::apierror::{self, ApiError}, storage::{error::StorageError, S
  async fn push_manifest(req: HttpRequest,info: web::Path<(String,String)>,store: web::Data<Storage>,file: Bytes) -> apierror::Result<HttpResponse> {
    
     let (repo,reff) = info.into_inner();
+    validate_name(&repo)?;
+    let reference: Reference = reff.parse()?;
    
     let content_len = file.len();
    
     let media_type = req.content_type();
 
-   let (digest,subject) = store.write_manifest(&repo,&reff,file,content_len,&media_type.to_string()).await?;
+   let (digest,subject) = store.write_manifest(&repo,&reference,file,content_len,&media_type.to_string()).await?;
     
-    Ok(HttpResponse::Created()
-                .append_header(("Location",format!("/{}/manifests/{}",repo,digest)))
-                .append_header(("OCI-Subject",subject)).finish())
+    // Docker clients read the digest of the stored manifest from the response
+    let mut resp = HttpResponse::Created();
+    resp.append_header(("Location",format!("/v2/{}/manifests/{}",repo,digest)))
+        .append_header(("Docker-Content-Digest",digest.to_string()));
+    if !subject.is_empty() {
+        resp.append_header(("OCI-Subject",subject));
+    }
+
+    Ok(resp.finish())
 }
 
 #[route("/{rep:.*}/blobs/uploads/{uuid}",method="GET")]
 async fn get_stale_blob_upload(info: web::Path<(String,String)>,store: web::Data<Storage>) -> apierror::Result<HttpResponse> {
-    
+
     let (repo,uuid) = info.into_inner();
-    
-    let n = match store.get_blob_upload(&repo, &uuid).await {
-    Ok(n) => n,
-    Err(e) => match e {
-        StorageError::ContenNotFound => {return  Err(ApiError::BlobUploadUnknown);},
-        e => {return Err(ApiError::Storage(e));}
-    }};
+    validate_name(&repo)?;
+    validate_upload(&uuid)?;
+
+    let n = upload_size(&store, &repo, &uuid).await?;
 
     let location: String = format!("/v2/{repo}/blobs/uploads/{uuid}");
-     
+
     Ok(HttpResponse::NoContent()
     .insert_header(("location",location))
-    .insert_header(("Range",format!("0-{}",n-1)))
+    .insert_header(("Range",upload_range(n)))
     .finish())
-    
+
 
 }
-//TODO: add the cross mount blob
+#[route("/{rep:.*}/blobs/uploads/{uuid}",method="DELETE")]
+async fn cancel_blob_upload(info: web::Path<(String,String)>,store: web::Data<Storage>) -> apierror::Result<HttpResponse> {
+
+    let (repo,uuid) = info.into_inner();
+    validate_name(&repo)?;
+    validate_upload(&uuid)?;
+
+    match store.cancel_blob_upload(&repo, &uuid).await {
+        Ok(()) => Ok(HttpResponse::NoContent().finish()),
+        Err(StorageError::ContenNotFound) => Err(ApiError::BlobUploadUnknown),
+        Err(e) => Err(e.into()),
+    }
+}
+
 #[route("/{rep:.*}/blobs/uploads/",method="POST")]
- async fn create_blob_uploads(req:HttpRequest,info: web::Path<String>,store: web::Data<Storage>,file: Bytes) -> apierror::Result<HttpResponse>{
+ async fn create_blob_uploads(req:HttpRequest,info: web::Path<String>,store: web::Data<Storage>,uploads: web::Data<Uploads>,payload: Payload) -> apierror::Result<HttpResponse>{
 
     let repo = info.into_inner();
-    let data = file.to_vec();
+    validate_name(&repo)?;
     let qs = req.query_string();
     let q = QString::from(qs);
 
-    let (digest,ok) = match q.get("digest") {
-        Some(digest) =>(digest,true),
-        None => ("",false),
-    };
+    let digest = q.get("digest").map(str::parse::<Digest>).transpose()?;
 
-    let (_,is_mount) = match q.get("mount") {
-        Some(mount) =>(mount,true),
-        None => ("",false),
-    };
-    
+    if let (Some(mount),Some(from)) = (q.get("mount"),q.get("from")) {
+        let mount: Digest = mount.parse()?;
+        validate_name(from)?;
+        if store.mount_blob(&repo, &from.to_string(), &mount).await? {
+            return Ok(HttpResponse::Created()
+                .insert_header(("location",format!("/v2/{repo}/blobs/{mount}")))
+                .insert_header(("Docker-Content-Digest",mount.to_string()))
+                .finish());
+        }
+    }
 
     let uuid =  store.new_blob_upload(&repo).await?;
     let location: String = format!("/v2/{repo}/blobs/uploads/{uuid}");
 
-    if is_mount {
-        return Ok(HttpResponse::Accepted().insert_header(("location",location)).finish());
-    }
-
     
-    if ok && !data.is_empty(){
-       
-       store.update_blob_upload(&repo, &uuid, 0, data).await.unwrap();
-       let _ = store.delete_blob_upload(&repo, &digest.to_string(), &uuid).await?;
+    if let Some(digest) = digest {
+
+       store.update_blob_upload(&repo, &uuid, 0, None, payload).await?;
+       store.delete_blob_upload(&repo, &digest, &uuid, content_length(&req)).await?;
   
        return Ok(HttpResponse::Created().insert_header(("location",format!("/v2/{repo}/blobs/{digest}"))).finish());
     
     }
 
 
-    Ok(HttpResponse::Accepted().insert_header(("location",location)).finish())
+    Ok(HttpResponse::Accepted()
+        .insert_header(("location",location))
+        .insert_header(("OCI-Chunk-Min-Length",uploads.chunk_min_length))
+        .finish())
 }
 
 
 #[route("/{rep:.*}/blobs/uploads/{uuid}",method="PUT")]
- async fn update_blob(req:HttpRequest,info: web::Path<(String,String)>,store: web::Data<Storage>,file: Bytes) -> apierror::Result<HttpResponse> {
+ async fn update_blob(req:HttpRequest,info: web::Path<(String,String)>,store: web::Data<Storage>,payload: Payload) -> apierror::Result<HttpResponse> {
     
     let (repo,uuid) = info.into_inner();
-    let data = file.to_vec();
-    
+    validate_name(&repo)?;
+    validate_upload(&uuid)?;
+
     let qs = req.query_string();
     let q = QString::from(qs);
     
@@ -106,60 +128,148 @@ async fn get_stale_blob_upload(info: web::Path<(String,String)>,store: web::Data
         None => ("",false),
     };
 
-    if ok  {
-        if  !data.is_empty() {
-            store.streamed_blob_upload(&repo, &uuid, data).await?;
-        }       
-       let _ = store.delete_blob_upload(&repo, &digest.to_string(), &uuid).await?;
-       let location = format!("/v2/{repo}/blobs/{digest}");
-       return Ok(HttpResponse::Created().insert_header(("location",location)).finish());
+    if !ok {
+        return Err(ApiError::DigestInvalid);
     }
+    let digest: Digest = digest.parse()?;
+
+    let staged = upload_size(&store, &repo, &uuid).await?;
 
-    let _ = store.delete_blob_upload(&repo, &digest.to_string(), &uuid);
-    Ok(HttpResponse::Created().finish())
+    // only a monolithic PUT carries the whole blob, chunked uploads announce the final chunk
+    let size = if staged == 0 { content_length(&req) } else { None };
+
+    append_chunk(&req, &store, &repo, &uuid, payload).await?;
+    store.delete_blob_upload(&repo, &digest, &uuid, size).await?;
+    let location = format!("/v2/{repo}/blobs/{digest}");
+    Ok(HttpResponse::Created().insert_header(("location",location)).finish())
 }
 
 #[route("/{rep:.*}/blobs/uploads/{uuid}",method="PATCH")]
- async fn update_blob_chunks(req:HttpRequest,info: web::Path<(String,String)>,store: web::Data<Storage>,file: Bytes) -> apierror::Result<HttpResponse> {
-    
+ async fn update_blob_chunks(req:HttpRequest,info: web::Path<(String,String)>,store: web::Data<Storage>,payload: Payload) -> apierror::Result<HttpResponse> {
+
     let (repo,uuid) = info.into_inner();
-    let data = file.to_vec();
-    let len = data.len();
+    validate_name(&repo)?;
+    validate_upload(&uuid)?;
     let location: String = format!("/v2/{repo}/blobs/uploads/{uuid}");
-    
 
-    /*TODO: logic for content range header blob upload */
-    let ((from,_),ok):((usize,usize),bool)= match req.headers().get(header::CONTENT_RANGE){
-         Some(r) =>{
-            (parse_range(r),true)
-         } ,
-         None => ((0,0),false),
-     };
-        
-     let mut n=0;
-
-    if ok {
-        store.update_blob_upload(&repo, &uuid, from as u64, data).await?;
-        n = len-1;
-        }
-        else {
-        store.streamed_blob_upload(&repo, &uuid, data).await?;    
-        }
+    let n = append_chunk(&req, &store, &repo, &uuid, payload).await?;
 
     Ok(HttpResponse::Accepted()
     .insert_header(("location",location))
-    .insert_header(("Range",format!("0-{}",n)))
+    .insert_header(("Range",upload_range(n)))
     .finish())
 
 }
 
+/// Appends the request body to an upload session and returns the staged size
+/// afterwards. A chunk sent with `Content-Range` must start where the staged data
+/// ends and be as long as the range says, otherwise it is refused with 416 and
+/// the range actually staged so the client can resume from there. A refused
+/// chunk is not appended in part.
+async fn append_chunk(req: &HttpRequest,store: &Storage,repo: &String,uuid: &String,payload: Payload) -> apierror::Result<u64> {
+
+    let staged = upload_size(store, repo, uuid).await?;
+    let location = format!("/v2/{repo}/blobs/uploads/{uuid}");
+
+    let range = match req.headers().get(header::CONTENT_RANGE) {
+        Some(r) => Some(parse_content_range(r).ok_or(ApiError::BlobUploadInvalid("malformed Content-Range".to_string()))?),
+        None => None,
+    };
+
+    if let Some((start,length)) = range {
+        if start != staged {
+            return Err(ApiError::UploadRangeInvalid { location, size: staged });
+        }
+        if content_length(req).is_some_and(|l| l != length) {
+            return Err(ApiError::BlobUploadInvalid("Content-Length does not match Content-Range".to_string()));
+        }
+    }
+
+    let written = match store.update_blob_upload(repo, uuid, staged, range.map(|(_,length)| length), payload).await {
+        Ok(n) => n,
+        Err(StorageError::ContenNotFound) => return Err(ApiError::BlobUploadUnknown),
+        Err(StorageError::RangeIsNotStatisfied | StorageError::SizeInvalid) => {
+            let size = upload_size(store, repo, uuid).await?;
+            return Err(ApiError::UploadRangeInvalid { location, size });
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(staged + written)
+}
+
+/// Upload locations are the uuids handed out by `new_blob_upload`, anything else
+/// can not name a session.
+fn validate_upload(uuid: &str) -> apierror::Result<()> {
+    Uuid::parse_str(uuid).map_err(|_| ApiError::BlobUploadUnknown)?;
+    Ok(())
+}
+
+async fn upload_size(store: &Storage,repo: &String,uuid: &String) -> apierror::Result<u64> {
+
+    match store.get_blob_upload(repo, uuid).await {
+        Ok(n) => Ok(n as u64),
+        Err(StorageError::ContenNotFound) => Err(ApiError::BlobUploadUnknown),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `Range` header of an upload session, the offset of the last staged byte is inclusive.
+fn upload_range(size: u64) -> String {
+    format!("0-{}",size.saturating_sub(1))
+}
+
+/// Parses a chunk `Content-Range` of the form `<start>-<end>`, also accepting the
+/// `bytes <start>-<end>/<total>` form of RFC 9110, into the start and length of
+/// the chunk. A range whose length does not fit into a `u64` is malformed.
+fn parse_content_range(header: &HeaderValue) -> Option<(u64,u64)> {
+    let s = header.to_str().ok()?.trim();
+    let s = s.strip_prefix("bytes ").unwrap_or(s);
+    let s = s.split_once('/').map_or(s, |(range,_)| range);
+
+    let (start,end) = s.split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end: u64 = end.trim().parse().ok()?;
+
+    if start > end {
+        return None;
+    }
+
+    Some((start,(end - start).checked_add(1)?))
+}
+
+fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers().get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
 
-fn parse_range(header: &HeaderValue) -> (usize,usize){
-    let s = header.to_str().unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let a:Vec<&str> = s.split("-").collect();
+    fn content_range(value: &str) -> Option<(u64,u64)> {
+        parse_content_range(&HeaderValue::from_str(value).unwrap())
+    }
+
+    #[test]
+    fn parses_chunk_ranges() {
+        assert_eq!(content_range("0-9"), Some((0,10)));
+        assert_eq!(content_range("10-10"), Some((10,1)));
+        assert_eq!(content_range("bytes 10-19/100"), Some((10,10)));
+        assert_eq!(content_range("bytes 10-19/*"), Some((10,10)));
+    }
 
-    let from: usize = a[0].parse().unwrap();
-    let to: usize = a[1].parse().unwrap();
-    (from,to)
+    #[test]
+    fn rejects_malformed_chunk_ranges() {
+        for value in ["", "10", "10-", "-9", "9-0", "a-b", "bytes=0-9"] {
+            assert_eq!(content_range(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn rejects_chunk_ranges_whose_length_overflows() {
+        assert_eq!(content_range(&format!("0-{}", u64::MAX)), None);
+        assert_eq!(content_range(&format!("1-{}", u64::MAX)), Some((1,u64::MAX)));
+    }
 }
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use actix_web::{http::header, route, web, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use log::warn;
+use qstring::QString;
+use regex::Regex;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{routes::{apierror::{self, ApiError}, auth::{Access, Claims, TokenAuth}}, storage::{common::now, policy::glob_to_regex}};
+
+/// Bcrypt hash of a password nobody has, checked for unknown users so they take
+/// as long to refuse as a wrong password and do not give away which users exist.
+const UNKNOWN_USER_HASH: &str = "$2b$12$qOomfz65CbeQmCPucf7adOmtf4l.YKHTm.Jr2JtwUV9snU5PvfxAa";
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(issue_token);
+}
+
+/// Actions the `users` are granted on the repositories matching one of the
+/// `repositories` globs, `catalog` also lets them list the repositories. A user
+/// `*` stands for every authenticated user, `anonymous` for requests without
+/// credentials.
+pub struct AccessPolicy {
+    users: Vec<String>,
+    repositories: Vec<Regex>,
+    actions: Vec<String>,
+    catalog: bool,
+}
+
+impl AccessPolicy {
+
+    pub fn new(users: &[String],repositories: &[String],actions: &[String],catalog: bool) -> Result<AccessPolicy,regex::Error> {
+
+        let repositories = repositories.iter().map(|r| glob_to_regex(r)).collect::<Result<Vec<Regex>,regex::Error>>()?;
+
+        Ok(AccessPolicy{users: users.to_vec(),repositories,actions: actions.to_vec(),catalog})
+    }
+
+    fn applies_to(&self,user: Option<&str>) -> bool {
+        match user {
+            Some(user) => self.users.iter().any(|u| u == "*" || u == user),
+            None => self.users.iter().any(|u| u == "anonymous"),
+        }
+    }
+
+    fn allows(&self,access: &Access,action: &str) -> bool {
+        match access.kind.as_str() {
+            "repository" => self.repositories.iter().any(|r| r.is_match(&access.name))
+                && self.actions.iter().any(|a| a == action || a == "*"),
+            "registry" => access.name == "catalog" && self.catalog,
+            _ => false,
+        }
+    }
+}
+
+/// Issues the tokens `TokenAuth` accepts, so no separate auth server is needed.
+/// Users are authenticated with basic credentials checked against their bcrypt
+/// hashes and get the part of the requested scopes their policies allow.
+pub struct TokenIssuer {
+    service: String,
+    issuer: String,
+    algorithm: Algorithm,
+    key: EncodingKey,
+    ttl: u64,
+    users: HashMap<String,String>,
+    policies: Vec<AccessPolicy>,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+    access_token: String,
+    expires_in: u64,
+}
+
+impl TokenIssuer {
+
+    /// `pem` is the RSA private key for RS256 tokens or the PKCS#8 EC P-256 private
+    /// key for ES256 tokens, `users` maps names to bcrypt password hashes.
+    pub fn new(service: &str,issuer: &str,pem: &[u8],ttl: u64,users: HashMap<String,String>,policies: Vec<AccessPolicy>) -> jsonwebtoken::errors::Result<TokenIssuer> {
+
+        let (algorithm,key) = match EncodingKey::from_rsa_pem(pem) {
+            Ok(key) => (Algorithm::RS256,key),
+            Err(_) => (Algorithm::ES256,EncodingKey::from_ec_pem(pem)?),
+        };
+
+        let token_issuer = TokenIssuer{service: service.to_string(),issuer: issuer.to_string(),algorithm,key,ttl,users,policies};
+
+        // a key the signer rejects is reported at startup rather than per request
+        token_issuer.issue("", Vec::new())?;
+
+        Ok(token_issuer)
+    }
+
+    /// Checks that `auth` accepts the tokens signed here, a `signing_key` that is
+    /// not the private half of the verification key would lock every client out.
+    pub fn check(&self,auth: &TokenAuth) -> jsonwebtoken::errors::Result<()> {
+        auth.verify(&self.issue("", Vec::new())?).map(|_| ())
+    }
+
+    async fn authenticate(&self,user: &str,password: String) -> bool {
+
+        let (known,hash) = match self.users.get(user) {
+            Some(hash) => (true,hash.clone()),
+            None => (false,UNKNOWN_USER_HASH.to_string()),
+        };
+
+        // bcrypt is slow on purpose, it is kept off the worker threads
+        match web::block(move || bcrypt::verify(password, &hash)).await {
+            Ok(Ok(valid)) => known && valid,
+            Ok(Err(e)) => {
+                warn!("password hash of user {user} can not be checked: {e}");
+                false
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// The part of `requested` the policies of `user` allow, scopes nothing is
+    /// allowed of are left out.
+    fn grant(&self,user: Option<&str>,requested: &[Access]) -> Vec<Access> {
+
+        let policies: Vec<&AccessPolicy> = self.policies.iter().filter(|p| p.applies_to(user)).collect();
+
+        requested.iter()
+            .filter_map(|access| {
+                let actions: Vec<&str> = access.actions.iter()
+                    .filter(|action| policies.iter().any(|p| p.allows(access, action)))
+                    .map(String::as_str)
+                    .collect();
+                (!actions.is_empty()).then(|| Access::new(&access.kind, &access.name, &actions))
+            })
+            .collect()
+    }
+
+    fn issue(&self,subject: &str,access: Vec<Access>) -> jsonwebtoken::errors::Result<String> {
+
+        let now = now();
+        let claims = Claims{
+            iss: self.issuer.clone(),
+            sub: subject.to_string(),
+            aud: self.service.clone().into(),
+            exp: now + self.ttl as i64,
+            nbf: Some(now),
+            iat: Some(now),
+            jti: Uuid::new_v4().to_string(),
+            access,
+        };
+
+        encode(&Header::new(self.algorithm), &claims, &self.key)
+    }
+}
+
+/// Token endpoint of the Docker token spec, `scope` may be given several times
+/// and hold several space separated scopes.
+#[route("/token",method="GET")]
+async fn issue_token(req: HttpRequest,issuer: web::Data<TokenIssuer>) -> apierror::Result<HttpResponse> {
+
+    let q = QString::from(req.query_string());
+
+    if q.get("service").is_some_and(|s| s != issuer.service) {
+        return Err(ApiError::Unsupported(format!("tokens are only issued for service {}",issuer.service)));
+    }
+
+    let user = match basic_credentials(&req) {
+        Some((user,password)) => {
+            if !issuer.authenticate(&user, password).await {
+                return Err(ApiError::Unauthorized{reason: "invalid username or password".to_string(),challenge: format!("Basic realm=\"{}\"",issuer.service)});
+            }
+            Some(user)
+        },
+        None => None,
+    };
+
+    let requested: Vec<Access> = q.to_pairs().into_iter()
+        .filter(|(key,_)| *key == "scope")
+        .flat_map(|(_,scope)| scope.split(' '))
+        .filter_map(parse_scope)
+        .collect();
+
+    let granted = issuer.grant(user.as_deref(), &requested);
+    let token = issuer.issue(user.as_deref().unwrap_or_default(), granted)
+        .map_err(|e| ApiError::Internal(format!("signing token: {e}")))?;
+
+    Ok(HttpResponse::Ok().json(TokenResponse{token: token.clone(),access_token: token,expires_in: issuer.ttl}))
+}
+
+/// Parses `<type>:<name>:<actions>`, names hold no `:` so the first and last one
+/// separate the parts.
+fn parse_scope(scope: &str) -> Option<Access> {
+
+    let (kind,rest) = scope.split_once(':')?;
+    let (name,actions) = rest.rsplit_once(':')?;
+    let actions: Vec<&str> = actions.split(',').filter(|a| !a.is_empty()).collect();
+
+    Some(Access::new(kind, name, &actions))
+}
+
+fn basic_credentials(req: &HttpRequest) -> Option<(String,String)> {
+
+    let (scheme,encoded) = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user,password) = decoded.split_once(':')?;
+
+    Some((user.to_string(),password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_scopes() {
+        assert_eq!(parse_scope("repository:library/alpine:pull,push"), Some(Access::new("repository", "library/alpine", &["pull","push"])));
+        assert_eq!(parse_scope("registry:catalog:*"), Some(Access::new("registry", "catalog", &["*"])));
+        assert_eq!(parse_scope("repository:a:"), Some(Access::new("repository", "a", &[])));
+        assert_eq!(parse_scope("repository:a"), None);
+        assert_eq!(parse_scope("repository"), None);
+    }
+
+    #[test]
+    fn grants_what_the_policies_allow() {
+        let alice = AccessPolicy::new(&strings(&["alice"]), &strings(&["a/*"]), &strings(&["pull","push"]), true).unwrap();
+        let anonymous = AccessPolicy::new(&strings(&["anonymous"]), &strings(&["public/*"]), &strings(&["pull"]), false).unwrap();
+        let everyone = AccessPolicy::new(&strings(&["*"]), &strings(&["shared"]), &strings(&["*"]), false).unwrap();
+        let issuer = TokenIssuer{service: String::new(),issuer: String::new(),algorithm: Algorithm::ES256,key: EncodingKey::from_secret(b""),ttl: 0,users: HashMap::new(),policies: vec![alice,anonymous,everyone]};
+
+        let requested = [
+            Access::new("repository", "a/x", &["pull","push","delete"]),
+            Access::new("repository", "public/x", &["pull","push"]),
+            Access::new("repository", "shared", &["delete"]),
+            Access::new("registry", "catalog", &["*"]),
+        ];
+
+        assert_eq!(issuer.grant(Some("alice"), &requested), vec![
+            Access::new("repository", "a/x", &["pull","push"]),
+            Access::new("repository", "shared", &["delete"]),
+            Access::new("registry", "catalog", &["*"]),
+        ]);
+        assert_eq!(issuer.grant(Some("bob"), &requested), vec![Access::new("repository", "shared", &["delete"])]);
+        assert_eq!(issuer.grant(None, &requested), vec![Access::new("repository", "public/x", &["pull"])]);
+    }
+    #[actix_web::test]
+    async fn checks_unknown_users_against_a_password_hash_too() {
+        let issuer = TokenIssuer{service: String::new(),issuer: String::new(),algorithm: Algorithm::ES256,key: EncodingKey::from_secret(b""),ttl: 0,
+            users: HashMap::from([("alice".to_string(),bcrypt::hash("secret", 4).unwrap())]),policies: Vec::new()};
+
+        assert!(bcrypt::verify("", UNKNOWN_USER_HASH).is_ok_and(|valid| !valid));
+        assert!(issuer.authenticate("alice", "secret".to_string()).await);
+        assert!(!issuer.authenticate("alice", "wrong".to_string()).await);
+        assert!(!issuer.authenticate("bob", "secret".to_string()).await);
+    }
+}
@@ -1,3 +1,4 @@
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::{Deserialize, Serialize};
 
@@ -8,4 +9,18 @@ pub struct Tags {
    pub tags: Vec<String>
 }
 
+#[derive(Deserialize,Serialize,Default,Debug)]
+pub struct Catalog {
+   pub repositories: Vec<String>
+}
+
+/// State kept next to the staged data of an upload session, times are unix seconds.
+#[derive(Deserialize,Serialize,Default,Debug)]
+pub struct UploadSession {
+   pub created_at: i64,
+   pub updated_at: i64
+}
 
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
@@ -0,0 +1,185 @@
+use std::{fmt::Display, str::FromStr};
+
+use sha2::{Digest as _, Sha256, Sha512};
+use thiserror::Error;
+
+/// Digest algorithms the registry can verify content with.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,Default)]
+pub enum Algorithm {
+    #[default]
+    Sha256,
+    Sha512
+}
+
+impl Algorithm {
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+        }
+    }
+
+    pub fn hasher(&self) -> Digester {
+        match self {
+            Algorithm::Sha256 => Digester::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Digester::Sha512(Sha512::new()),
+        }
+    }
+
+    /// Length of the lowercase hex encoding the OCI spec mandates for the algorithm.
+    fn encoded_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+}
+
+#[derive(Debug,Error,PartialEq,Eq)]
+pub enum DigestError {
+    #[error("digest does not match the OCI digest grammar")]
+    Invalid,
+
+    #[error("digest algorithm {0} is not supported")]
+    Unsupported(String),
+}
+
+/// A content digest of the form `<algorithm>:<encoded>` as defined by the OCI
+/// image spec. Parsing validates the grammar and the encoding of the registered
+/// algorithms, so a `Digest` is always safe to use as a storage path component.
+#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+pub struct Digest {
+    algorithm: Algorithm,
+    encoded: String
+}
+
+impl Digest {
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub fn encoded(&self) -> &str {
+        &self.encoded
+    }
+
+    pub fn from_content(algorithm: Algorithm,data: &[u8]) -> Digest {
+        let mut hasher = algorithm.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+impl FromStr for Digest {
+    type Err = DigestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+
+        let (algorithm,encoded) = s.split_once(':').ok_or(DigestError::Invalid)?;
+
+        let valid_algorithm = algorithm
+            .split(['+','.','_','-'])
+            .all(|c| !c.is_empty() && c.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit()));
+        let valid_encoded = !encoded.is_empty()
+            && encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'=' || b == b'_' || b == b'-');
+
+        if !valid_algorithm || !valid_encoded {
+            return Err(DigestError::Invalid);
+        }
+
+        let algorithm = match algorithm {
+            "sha256" => Algorithm::Sha256,
+            "sha512" => Algorithm::Sha512,
+            a => return Err(DigestError::Unsupported(a.to_string())),
+        };
+
+        if encoded.len() != algorithm.encoded_len() || !encoded.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+            return Err(DigestError::Invalid);
+        }
+
+        Ok(Digest{algorithm,encoded: encoded.to_string()})
+    }
+}
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f,"{}:{}",self.algorithm.as_str(),self.encoded)
+    }
+}
+
+/// Incremental hasher for one of the supported [`Algorithm`]s.
+pub enum Digester {
+    Sha256(Sha256),
+    Sha512(Sha512)
+}
+
+impl Digester {
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Digester::Sha256(_) => Algorithm::Sha256,
+            Digester::Sha512(_) => Algorithm::Sha512,
+        }
+    }
+
+    pub fn update(&mut self,data: &[u8]) {
+        match self {
+            Digester::Sha256(h) => h.update(data),
+            Digester::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Digest {
+        let (algorithm,encoded) = match self {
+            Digester::Sha256(h) => (Algorithm::Sha256,format!("{:x}",h.finalize())),
+            Digester::Sha512(h) => (Algorithm::Sha512,format!("{:x}",h.finalize())),
+        };
+
+        Digest{algorithm,encoded}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256_EMPTY: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn parses_registered_algorithms() {
+        let digest: Digest = SHA256_EMPTY.parse().unwrap();
+        assert_eq!(digest.algorithm(), Algorithm::Sha256);
+        assert_eq!(digest.to_string(), SHA256_EMPTY);
+
+        let sha512 = format!("sha512:{}", "a".repeat(128));
+        assert_eq!(sha512.parse::<Digest>().unwrap().algorithm(), Algorithm::Sha512);
+    }
+
+    #[test]
+    fn rejects_malformed_digests() {
+        for digest in ["", "sha256", "sha256:", ":abc", "SHA256:abc", "sha256:../../etc/passwd",
+                       "sha256:E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855",
+                       "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85",
+                       "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b8555",
+                       "sha256+:abc", "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b85g"] {
+            assert_eq!(digest.parse::<Digest>(), Err(DigestError::Invalid), "{digest}");
+        }
+    }
+
+    #[test]
+    fn reports_unknown_algorithms_as_unsupported() {
+        assert_eq!("md5:d41d8cd98f00b204e9800998ecf8427e".parse::<Digest>(), Err(DigestError::Unsupported("md5".to_string())));
+        assert_eq!("multihash+base58:QmRZxt2b1FVZPNqd8hsiykDL3TdBDeTSPX9Kv46HmX4Gx8".parse::<Digest>(), Err(DigestError::Unsupported("multihash+base58".to_string())));
+    }
+
+    #[test]
+    fn hashes_content() {
+        assert_eq!(Digest::from_content(Algorithm::Sha256, b"").to_string(), SHA256_EMPTY);
+
+        let mut hasher = Algorithm::Sha512.hasher();
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        assert_eq!(hasher.finalize(), Digest::from_content(Algorithm::Sha512, b"hello world"));
+    }
+}
@@ -1,7 +1,7 @@
 
-use std::fmt::Display;
-
 use oci_spec::OciSpecError;
+
+use super::digest::DigestError;
 use thiserror::Error;
 
 
@@ -11,21 +11,50 @@ pub type Result<T> = std::result::Result<T,StorageError>;
 #[derive(Debug,Error)]
 pub enum StorageError {
     
+    #[error("storage operator error: {0}")]
     OperatorError(opendal::Error),
 
+    #[error("oci spec error: {0}")]
     OciSpec(#[from]OciSpecError),
     
+    #[error("serialization error: {0}")]
     SerdeParse(#[from] serde_json::Error),
 
+    #[error("{0}")]
+    Digest(#[from] DigestError),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("request body could not be read: {0}")]
+    Payload(String),
+
+    #[error("content not found")]
     ContenNotFound,
     
-    RangeIsNotStatisfied
-}
+    #[error("range is not satisfiable")]
+    RangeIsNotStatisfied,
 
-impl Display for StorageError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f,"{}",self)
-    }
+    #[error("content does not match the given digest")]
+    DigestInvalid,
+
+    #[error("content does not match the given size")]
+    SizeInvalid,
+
+    #[error("{0}")]
+    Denied(String),
+
+    #[error("manifest invalid: {0}")]
+    ManifestInvalid(String),
+
+    #[error("manifest references unknown blobs: {}", .0.join(", "))]
+    ManifestBlobUnknown(Vec<String>),
+
+    #[error("storage backend is rate limiting requests")]
+    RateLimited,
+
+    #[error("upstream registry: {0}")]
+    Upstream(String)
 }
 
 impl From<opendal::Error> for StorageError{
@@ -33,7 +62,14 @@ impl From<opendal::Error> for StorageError{
         match value.kind() {
             opendal::ErrorKind::NotFound => StorageError::ContenNotFound,
             opendal::ErrorKind::RangeNotSatisfied => StorageError::RangeIsNotStatisfied,
+            opendal::ErrorKind::RateLimited => StorageError::RateLimited,
             _ => StorageError::OperatorError(value),
         }
     }
 }
+
+impl From<reqwest::Error> for StorageError{
+    fn from(value: reqwest::Error) -> Self {
+        StorageError::Upstream(value.to_string())
+    }
+}
@@ -0,0 +1,342 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display};
+
+use log::warn;
+use oci_spec::image::Descriptor;
+use super::{common::now, digest::Digest, error::Result, manifest::ManifestRefs, storage::{ref_name, referrers_tag}, Storage};
+
+#[derive(Debug,Clone,Default)]
+pub struct GcOptions {
+    /// Only report what would be removed.
+    pub dry_run: bool,
+    /// Links and blobs younger than this many seconds are never removed, so
+    /// blobs of a push that has not sent its manifest yet survive.
+    pub grace_period: u64,
+    /// Also remove manifests that carry no tag and are neither referenced by a
+    /// tagged index nor referrers of a kept manifest. Tags of the referrers tag
+    /// schema do not count, they follow the referrers they list.
+    pub prune_untagged: bool,
+}
+
+#[derive(Debug,Default)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub repositories: usize,
+    pub manifests_pruned: Vec<String>,
+    pub links_removed: Vec<String>,
+    pub blobs_removed: Vec<String>,
+    pub bytes_freed: u64,
+}
+
+impl Display for GcReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verb = if self.dry_run { "would remove" } else { "removed" };
+        writeln!(f,"garbage collection over {} repositories {verb} {} manifests, {} links and {} blobs ({} bytes)",
+            self.repositories,self.manifests_pruned.len(),self.links_removed.len(),self.blobs_removed.len(),self.bytes_freed)?;
+        for m in &self.manifests_pruned {
+            writeln!(f,"  manifest {m}")?;
+        }
+        for l in &self.links_removed {
+            writeln!(f,"  link {l}")?;
+        }
+        for b in &self.blobs_removed {
+            writeln!(f,"  blob {b}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage {
+
+/// Mark and sweep over all repositories. Every manifest listed in a repository's
+/// `index.json` is a root (only tagged ones with `prune_untagged`), the blobs
+/// and child manifests it references are marked, then unmarked links are removed
+/// and finally the blobs of the shared pool that no repository links anymore.
+pub async fn garbage_collect(&self,opts: &GcOptions) -> Result<GcReport> {
+
+    let mut report = GcReport{dry_run: opts.dry_run, ..Default::default()};
+    let cutoff = now() - opts.grace_period as i64;
+
+    self.begin_gc();
+
+    let mut linked = HashSet::new();
+    let result = async {
+        for repo in self.list_repositories().await? {
+            self.collect_repository(&repo, opts, cutoff, &mut linked, &mut report).await?;
+            report.repositories += 1;
+        }
+        self.sweep_blobs(opts, cutoff, &linked, &mut report).await
+    }.await;
+
+    self.end_gc();
+    result?;
+
+    Ok(report)
+}
+
+async fn collect_repository(&self,repo:&String,opts: &GcOptions,cutoff: i64,linked: &mut HashSet<Digest>,report: &mut GcReport) -> Result<()> {
+
+    let _lock = self.lock_index(repo).await;
+    let links: HashMap<Digest,i64> = self.list_links(repo).await?.into_iter().collect();
+    let mut index = self.get_image_index(repo).await?;
+    let descriptors: Vec<(Digest,Descriptor)> = index.manifests().iter()
+        .filter_map(|d| d.digest().parse().ok().map(|digest| (digest,d.clone())))
+        .collect();
+
+    let mut refs: HashMap<Digest,ManifestRefs> = HashMap::new();
+    let mut marked = HashSet::new();
+
+    // referrers indexes behind the referrers tag schema follow their referrers
+    // instead of keeping them
+    let is_recent = |digest: &Digest| links.get(digest).is_some_and(|t| *t > cutoff);
+    let roots: Vec<Digest> = descriptors.iter()
+        .filter(|(digest,d)| !opts.prune_untagged || ref_name(d).is_some_and(|t| !is_referrers_tag(t)) || is_recent(digest))
+        .map(|(digest,_)| digest.clone())
+        .collect();
+    self.mark_manifests(repo, roots, &mut marked, &mut refs).await;
+
+    // referrers of a kept manifest are kept with it, which can in turn keep more referrers
+    if opts.prune_untagged {
+        loop {
+            let mut referrers = Vec::new();
+            for (digest,_) in descriptors.iter().filter(|(digest,d)| !marked.contains(digest) && !is_referrers_index(d)) {
+                if !refs.contains_key(digest) {
+                    refs.insert(digest.clone(), self.read_manifest_refs(repo, digest).await);
+                }
+                if refs[digest].subject().is_some_and(|s| marked.contains(&s)) {
+                    referrers.push(digest.clone());
+                }
+            }
+            if referrers.is_empty() {
+                break;
+            }
+            self.mark_manifests(repo, referrers, &mut marked, &mut refs).await;
+        }
+
+        let mut seen = HashSet::new();
+        let pruned: Vec<&(Digest,Descriptor)> = descriptors.iter()
+            .filter(|(digest,d)| !marked.contains(digest) && !is_referrers_index(d) && seen.insert(digest.clone()))
+            .collect();
+
+        report.manifests_pruned.extend(pruned.iter().map(|(d,_)| format!("{repo}@{d}")));
+
+        let mut kept: Vec<Descriptor> = descriptors.iter()
+            .filter(|(digest,d)| marked.contains(digest) || is_referrers_index(d))
+            .map(|(_,d)| d.clone())
+            .collect();
+
+        if !pruned.is_empty() && !opts.dry_run {
+            // pruned referrers leave the referrers of their subject like deleted ones do
+            let mut pending = Vec::new();
+            for (digest,descriptor) in &pruned {
+                if let Some(subject) = refs.get(digest).and_then(|r| r.subject()) {
+                    pending.push(self.update_referrers(repo, &mut kept, &subject, descriptor, false).await?);
+                }
+            }
+            index.set_manifests(kept.clone());
+            self.update_image_index(repo, index).await?;
+            for referrers in pending {
+                self.write_referrers(repo, referrers).await?;
+            }
+        }
+
+        marked.extend(kept.iter().filter(|d| is_referrers_index(d)).filter_map(|d| d.digest().parse().ok()));
+    }
+
+    for (digest,modified) in links {
+        if marked.contains(&digest) || modified > cutoff {
+            linked.insert(digest);
+            continue;
+        }
+        // the link may have been written again since it was listed
+        if self.stat_link(repo, &digest).await?.is_some_and(|t| t > cutoff) {
+            linked.insert(digest);
+            continue;
+        }
+        report.links_removed.push(format!("{repo}@{digest}"));
+        if !opts.dry_run {
+            self.unlink_blob(repo, &digest).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn mark_manifests(&self,repo:&String,roots: Vec<Digest>,marked: &mut HashSet<Digest>,refs: &mut HashMap<Digest,ManifestRefs>) {
+
+    let mut pending = roots;
+
+    while let Some(digest) = pending.pop() {
+        if !marked.insert(digest.clone()) {
+            continue;
+        }
+        if !refs.contains_key(&digest) {
+            refs.insert(digest.clone(), self.read_manifest_refs(repo, &digest).await);
+        }
+        let manifest = &refs[&digest];
+        marked.extend(manifest.blobs());
+        pending.extend(manifest.children());
+    }
+}
+
+async fn read_manifest_refs(&self,repo:&String,digest:&Digest) -> ManifestRefs {
+
+    let parsed = match self.get_blobs(repo, digest).await {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+
+    parsed.unwrap_or_else(|e| {
+        warn!("garbage collection can not read manifest {repo}@{digest}: {e}");
+        ManifestRefs::default()
+    })
+}
+
+async fn sweep_blobs(&self,opts: &GcOptions,cutoff: i64,linked: &HashSet<Digest>,report: &mut GcReport) -> Result<()> {
+
+    // links written while the repositories were walked keep their blobs alive,
+    // holding the guard keeps new links from racing with the removal
+    let (_guard,recent) = self.lock_sweep().await;
+
+    for entry in self.primary.list("blobs/").await? {
+        let Ok(digest) = entry.name().parse::<Digest>() else {
+            continue;
+        };
+        if linked.contains(&digest) || recent.contains(&digest) {
+            continue;
+        }
+
+        let meta = self.primary.stat(entry.path()).await?;
+        if meta.last_modified().is_some_and(|t| t.timestamp() > cutoff) {
+            continue;
+        }
+
+        report.bytes_freed += meta.content_length();
+        report.blobs_removed.push(digest.to_string());
+        if !opts.dry_run {
+            self.primary.delete(&Self::create_blob_path(&digest)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+}
+
+/// Tags of the referrers tag schema, `<alg>-<encoded>` of a subject.
+fn is_referrers_tag(tag: &str) -> bool {
+    tag.replacen('-', ":", 1).parse::<Digest>().is_ok_and(|d| referrers_tag(&d) == tag)
+}
+
+fn is_referrers_index(d: &Descriptor) -> bool {
+    ref_name(d).is_some_and(|t| is_referrers_tag(t))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Bytes;
+    use oci_spec::image::MediaType;
+
+    use super::*;
+    use crate::storage::{reference::Reference, storage::tests::{push_blob, push_image, temp_storage}};
+
+    fn opts(grace_period: u64,dry_run: bool,prune_untagged: bool) -> GcOptions {
+        GcOptions{dry_run,grace_period,prune_untagged}
+    }
+
+    async fn tags(store: &Storage,repo: &str) -> Vec<String> {
+        let index = store.get_image_index(&repo.to_string()).await.unwrap();
+        index.manifests().iter().filter_map(ref_name).cloned().collect()
+    }
+
+    async fn has_manifest(store: &Storage,repo: &str,digest: &Digest) -> bool {
+        store.get_manifest(&repo.to_string(), &Reference::Digest(digest.clone())).await.is_ok()
+    }
+
+    #[actix_web::test]
+    async fn keeps_recent_links_and_blobs() {
+        let store = temp_storage();
+        push_image(&store, "app", Some("v1"), None).await;
+        let orphan = push_blob(&store, "app", b"orphan").await;
+
+        let report = store.garbage_collect(&opts(3600, false, false)).await.unwrap();
+        assert!(report.links_removed.is_empty() && report.blobs_removed.is_empty());
+
+        let report = store.garbage_collect(&opts(0, false, false)).await.unwrap();
+        assert_eq!(report.links_removed, vec![format!("app@{orphan}")]);
+        assert_eq!(report.blobs_removed, vec![orphan.to_string()]);
+        assert!(store.stat_blob(&"app".to_string(), &orphan).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn dry_run_removes_nothing() {
+        let store = temp_storage();
+        let image = push_image(&store, "app", None, None).await;
+        let orphan = push_blob(&store, "app", b"orphan").await;
+
+        let report = store.garbage_collect(&opts(0, true, true)).await.unwrap();
+        assert_eq!(report.manifests_pruned, vec![format!("app@{image}")]);
+        assert_eq!(report.blobs_removed.len(), 3);
+
+        assert!(has_manifest(&store, "app", &image).await);
+        assert!(store.stat_blob(&"app".to_string(), &orphan).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn keeps_children_of_tagged_indexes() {
+        let store = temp_storage();
+        let child = push_image(&store, "app", None, None).await;
+        let (_,data) = store.get_manifest(&"app".to_string(), &Reference::Digest(child.clone())).await.unwrap();
+        let index = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [{"mediaType": "application/vnd.oci.image.manifest.v1+json","digest": child.to_string(),"size": data.len()}]
+        })).unwrap();
+        let size = index.len();
+        store.write_manifest(&"app".to_string(), &Reference::Tag("v1".to_string()), Bytes::from(index), size, &String::from(MediaType::ImageIndex)).await.unwrap();
+
+        let report = store.garbage_collect(&opts(0, false, true)).await.unwrap();
+        assert!(report.manifests_pruned.is_empty() && report.blobs_removed.is_empty());
+        assert!(has_manifest(&store, "app", &child).await);
+    }
+
+    #[actix_web::test]
+    async fn keeps_referrers_of_kept_manifests() {
+        let store = temp_storage();
+        let subject = push_image(&store, "app", Some("v1"), None).await;
+        let signature = push_image(&store, "app", None, Some(&subject)).await;
+        let attestation = push_image(&store, "app", None, Some(&signature)).await;
+
+        let report = store.garbage_collect(&opts(0, false, true)).await.unwrap();
+        assert!(report.manifests_pruned.is_empty() && report.blobs_removed.is_empty());
+        assert!(has_manifest(&store, "app", &signature).await);
+        assert!(has_manifest(&store, "app", &attestation).await);
+    }
+
+    #[actix_web::test]
+    async fn prunes_untagged_manifests_and_their_referrers() {
+        let store = temp_storage();
+        let repo = "app".to_string();
+        push_image(&store, "app", Some("v1"), None).await;
+        let subject = push_image(&store, "app", None, None).await;
+        let referrer = push_image(&store, "app", None, Some(&subject)).await;
+        assert!(tags(&store, "app").await.contains(&referrers_tag(&subject)));
+
+        let report = store.garbage_collect(&opts(0, false, true)).await.unwrap();
+        assert_eq!(report.manifests_pruned.len(), 2);
+
+        assert!(!has_manifest(&store, "app", &subject).await);
+        assert!(!has_manifest(&store, "app", &referrer).await);
+        assert_eq!(tags(&store, "app").await, vec!["v1".to_string()]);
+        assert!(store.get_referrers(&repo, &subject).await.unwrap().manifests().is_empty());
+        // both manifests, their configs and the referrers index of the subject
+        assert_eq!(report.blobs_removed.len(), 5);
+    }
+
+    #[test]
+    fn recognizes_referrers_tags() {
+        let digest = Digest::from_content(crate::storage::digest::Algorithm::default(), b"subject");
+        assert!(is_referrers_tag(&referrers_tag(&digest)));
+        assert!(!is_referrers_tag("v1"));
+        assert!(!is_referrers_tag("sha256-latest"));
+    }
+}
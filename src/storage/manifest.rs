@@ -0,0 +1,48 @@
+use serde::Deserialize;
+
+use super::digest::Digest;
+
+/// The parts of a manifest, image index or Docker manifest that point at other content.
+#[derive(Deserialize,Default)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct ManifestRefs {
+    pub media_type: Option<String>,
+    pub config: Option<Ref>,
+    #[serde(default)]
+    pub layers: Vec<Ref>,
+    #[serde(default)]
+    pub manifests: Vec<Ref>,
+    #[serde(default)]
+    pub blobs: Vec<Ref>,
+    subject: Option<Ref>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct Ref {
+    pub media_type: Option<String>,
+    pub digest: String,
+    #[serde(default)]
+    pub size: u64
+}
+
+impl ManifestRefs {
+
+    /// Descriptors of the blobs referenced directly, their content is not looked into.
+    pub fn blob_refs(&self) -> impl Iterator<Item = &Ref> + '_ {
+        self.config.iter().chain(self.layers.iter()).chain(self.blobs.iter())
+    }
+
+    pub fn blobs(&self) -> impl Iterator<Item = Digest> + '_ {
+        self.blob_refs().filter_map(|r| r.digest.parse().ok())
+    }
+
+    /// Child manifests of an index, they are marked recursively.
+    pub fn children(&self) -> impl Iterator<Item = Digest> + '_ {
+        self.manifests.iter().filter_map(|r| r.digest.parse().ok())
+    }
+
+    pub fn subject(&self) -> Option<Digest> {
+        self.subject.as_ref().and_then(|r| r.digest.parse().ok())
+    }
+}
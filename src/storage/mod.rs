@@ -1,5 +1,14 @@
 pub mod common;
+#[allow(clippy::module_inception)]
 pub mod storage;
 pub mod error;
+pub mod digest;
+pub mod reference;
+pub mod gc;
+mod manifest;
+pub mod policy;
+pub mod registry;
+pub mod upstream;
+pub mod replication;
 
 pub use storage::*;
\ No newline at end of file
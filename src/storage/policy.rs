@@ -0,0 +1,94 @@
+use regex::Regex;
+
+/// Tags of the repositories matching `repository` that can not be moved or
+/// deleted once pushed.
+#[derive(Debug,Clone)]
+pub struct ImmutableTags {
+    repository: Regex,
+    tags: Vec<Regex>
+}
+
+impl ImmutableTags {
+
+    /// `repository` and `globs` are glob patterns where `*` matches any run of
+    /// characters and `?` a single one, `patterns` are regular expressions
+    /// matched against the whole tag.
+    pub fn new(repository: &str,globs: &[String],patterns: &[String]) -> Result<ImmutableTags,regex::Error> {
+
+        let tags = globs.iter().map(|g| glob_to_regex(g))
+            .chain(patterns.iter().map(|p| Regex::new(&format!("^(?:{p})$"))))
+            .collect::<Result<Vec<Regex>,regex::Error>>()?;
+
+        Ok(ImmutableTags{repository: glob_to_regex(repository)?,tags})
+    }
+}
+
+/// The immutable tag rules of all repositories, a tag is immutable when any
+/// rule of its repository matches it.
+#[derive(Debug,Clone,Default)]
+pub struct TagPolicy {
+    rules: Vec<ImmutableTags>
+}
+
+impl TagPolicy {
+
+    pub fn new(rules: Vec<ImmutableTags>) -> TagPolicy {
+        TagPolicy{rules}
+    }
+
+    pub fn is_immutable(&self,repo: &str,tag: &str) -> bool {
+        self.rules.iter()
+            .filter(|r| r.repository.is_match(repo))
+            .any(|r| r.tags.iter().any(|t| t.is_match(tag)))
+    }
+}
+
+pub fn glob_to_regex(glob: &str) -> Result<Regex,regex::Error> {
+    let pattern = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+    Regex::new(&format!("^{pattern}$"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_globs_literally_apart_from_wildcards() {
+        let glob = glob_to_regex("team/app-?.v*").unwrap();
+        assert!(glob.is_match("team/app-1.v2"));
+        assert!(glob.is_match("team/app-x.v"));
+        assert!(!glob.is_match("team/app-10.v2"));
+        assert!(!glob.is_match("team/app-1xv2"));
+        assert!(!glob.is_match("other/team/app-1.v2"));
+
+        assert!(glob_to_regex("a+b").unwrap().is_match("a+b"));
+        assert!(!glob_to_regex("a+b").unwrap().is_match("aab"));
+    }
+
+    #[test]
+    fn matches_whole_tags_against_patterns() {
+        let policy = TagPolicy::new(vec![ImmutableTags::new("app", &[], &strings(&[r"v\d+\.\d+", "stable|lts"])).unwrap()]);
+        assert!(policy.is_immutable("app", "v1.2"));
+        assert!(policy.is_immutable("app", "lts"));
+        assert!(!policy.is_immutable("app", "v1.2-rc"));
+        assert!(!policy.is_immutable("app", "old-stable"));
+        assert!(ImmutableTags::new("app", &[], &strings(&["("])).is_err());
+    }
+
+    #[test]
+    fn applies_rules_to_their_repositories() {
+        let policy = TagPolicy::new(vec![
+            ImmutableTags::new("team/*", &strings(&["*"]), &[]).unwrap(),
+            ImmutableTags::new("app", &strings(&["v*"]), &[]).unwrap(),
+        ]);
+        assert!(policy.is_immutable("team/x", "latest"));
+        assert!(policy.is_immutable("app", "v1"));
+        assert!(!policy.is_immutable("app", "latest"));
+        assert!(!policy.is_immutable("other", "v1"));
+        assert!(!TagPolicy::default().is_immutable("app", "v1"));
+    }
+}
@@ -0,0 +1,53 @@
+use std::{fmt::Display, str::FromStr};
+
+use thiserror::Error;
+
+use super::digest::{Digest, DigestError};
+
+/// The `<reference>` of a manifest request, either a tag or a digest.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Reference {
+    Tag(String),
+    Digest(Digest)
+}
+
+#[derive(Debug,Error,PartialEq,Eq)]
+pub enum ReferenceError {
+    #[error("tag does not match the OCI tag grammar")]
+    InvalidTag,
+
+    #[error(transparent)]
+    Digest(#[from] DigestError),
+}
+
+impl FromStr for Reference {
+    type Err = ReferenceError;
+
+    /// Anything holding a `:` is taken as a digest, everything else must match the
+    /// tag grammar `[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+
+        if s.contains(':') {
+            return Ok(Reference::Digest(s.parse()?));
+        }
+
+        let valid_tag = s.len() <= 128
+            && s.bytes().next().is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_')
+            && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'.' || b == b'-');
+
+        if !valid_tag {
+            return Err(ReferenceError::InvalidTag);
+        }
+
+        Ok(Reference::Tag(s.to_string()))
+    }
+}
+
+impl Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reference::Tag(t) => write!(f,"{t}"),
+            Reference::Digest(d) => write!(f,"{d}"),
+        }
+    }
+}
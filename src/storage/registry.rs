@@ -0,0 +1,200 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+
+use reqwest::{header, Client, Method, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+
+use super::{digest::Digest, error::{Result, StorageError}};
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+    expires_in: Option<u64>
+}
+
+/// Client of another distribution-compatible registry, shared by the pull-through
+/// cache and replication. Bearer challenges are answered through the token
+/// endpoint the registry names, the tokens are kept per repository until they expire.
+pub struct RegistryClient {
+    client: Client,
+    url: String,
+    credentials: Option<(String,String)>,
+    tokens: Mutex<HashMap<String,(String,Instant)>>
+}
+
+impl RegistryClient {
+
+    pub fn new(url: &str,credentials: Option<(String,String)>) -> reqwest::Result<RegistryClient> {
+        let client = Client::builder()
+            .user_agent(concat!("ferridock/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+
+        Ok(RegistryClient{client,url: url.trim_end_matches('/').to_string(),credentials,tokens: Mutex::new(HashMap::new())})
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Request for `path` below `/v2/<repo>/`.
+    pub fn request(&self,method: Method,repo: &str,path: &str) -> RequestBuilder {
+        self.client.request(method, format!("{}/v2/{repo}/{path}",self.url))
+    }
+
+    /// Request for a location the registry handed out, which may be relative to it.
+    pub fn request_location(&self,method: Method,location: &str) -> RequestBuilder {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return self.client.request(method, location);
+        }
+        self.client.request(method, format!("{}{location}",self.url))
+    }
+
+    /// Sends a request on behalf of `repo`. A challenge to the first attempt is
+    /// answered once, with a token for bearer challenges and with the configured
+    /// credentials for basic ones. Requests with a streamed body can not be sent
+    /// twice, they rely on a token obtained by an earlier request.
+    pub async fn send(&self,repo: &str,req: RequestBuilder) -> Result<Response> {
+
+        let retry = req.try_clone();
+        let req = match self.cached_token(repo) {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        };
+
+        let resp = req.send().await?;
+        if resp.status() != StatusCode::UNAUTHORIZED {
+            return Ok(resp);
+        }
+
+        let Some(retry) = retry else {
+            return Ok(resp);
+        };
+        let Some((scheme,params)) = resp.headers().get(header::WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_challenge) else {
+            return Ok(resp);
+        };
+
+        let retry = match (scheme.as_str(),&self.credentials) {
+            ("bearer",_) => retry.bearer_auth(self.fetch_token(repo, &params).await?),
+            ("basic",Some((username,password))) => retry.basic_auth(username, Some(password)),
+            _ => return Ok(resp),
+        };
+
+        Ok(retry.send().await?)
+    }
+
+    async fn fetch_token(&self,repo: &str,params: &HashMap<String,String>) -> Result<String> {
+
+        let realm = params.get("realm").ok_or(StorageError::Upstream("bearer challenge without realm".to_string()))?;
+        let scope = params.get("scope").cloned().unwrap_or_else(|| format!("repository:{repo}:pull"));
+
+        let mut query = vec![("scope",scope.as_str())];
+        if let Some(service) = params.get("service") {
+            query.push(("service",service.as_str()));
+        }
+
+        let mut req = self.client.get(realm).query(&query);
+        if let Some((username,password)) = &self.credentials {
+            req = req.basic_auth(username, Some(password));
+        }
+
+        let resp = req.send().await?;
+        if !resp.status().is_success() {
+            return Err(StorageError::Upstream(format!("token endpoint {realm} answered {}",resp.status())));
+        }
+
+        let body: TokenResponse = resp.json().await?;
+        let token = body.token.or(body.access_token).ok_or(StorageError::Upstream(format!("token endpoint {realm} sent no token")))?;
+
+        // renewed a little early so a token never expires on its way to the registry
+        let lifetime = Duration::from_secs(body.expires_in.unwrap_or(60).saturating_sub(10));
+        if let Ok(mut tokens) = self.tokens.lock() {
+            tokens.insert(repo.to_string(), (token.clone(),Instant::now() + lifetime));
+        }
+
+        Ok(token)
+    }
+
+    fn cached_token(&self,repo: &str) -> Option<String> {
+        let tokens = self.tokens.lock().ok()?;
+        tokens.get(repo).filter(|(_,expires)| *expires > Instant::now()).map(|(token,_)| token.clone())
+    }
+}
+
+/// Passes successful responses on, `None` for a 404 and an error for anything else.
+pub fn found(resp: Response) -> reqwest::Result<Option<Response>> {
+    if resp.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    resp.error_for_status().map(Some)
+}
+
+pub fn content_digest(resp: &Response) -> Option<Digest> {
+    resp.headers().get("Docker-Content-Digest").and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok())
+}
+
+pub fn content_length(resp: &Response) -> Option<u64> {
+    resp.headers().get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()).and_then(|v| v.parse().ok())
+}
+
+/// Splits a `WWW-Authenticate` challenge such as
+/// `Bearer realm="https://auth.example/token",service="registry",scope="repository:a:pull"`
+/// into its lowercased scheme and parameters.
+fn parse_challenge(header: &str) -> Option<(String,HashMap<String,String>)> {
+
+    let (scheme,rest) = header.trim().split_once(' ').unwrap_or((header.trim(),""));
+    let mut params = HashMap::new();
+
+    let mut rest = rest.trim();
+    while !rest.is_empty() {
+        let (key,value) = rest.split_once('=')?;
+        let value = value.trim_start();
+
+        let (value,tail) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end],&quoted[end + 1..])
+            },
+            None => value.split_once(',').map_or((value,""), |(v,t)| (v,t)),
+        };
+
+        params.insert(key.trim().to_ascii_lowercase(), value.to_string());
+        rest = tail.trim_start_matches([',',' ']);
+    }
+
+    Some((scheme.to_ascii_lowercase(),params))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bearer_challenges() {
+        let (scheme,params) = parse_challenge(r#"Bearer realm="https://auth.example/token",service="registry",scope="repository:a:pull,push""#).unwrap();
+
+        assert_eq!(scheme, "bearer");
+        assert_eq!(params["realm"], "https://auth.example/token");
+        assert_eq!(params["service"], "registry");
+        assert_eq!(params["scope"], "repository:a:pull,push");
+    }
+
+    #[test]
+    fn parses_unquoted_and_empty_challenges() {
+        let (scheme,params) = parse_challenge("Basic Realm=registry, charset=UTF-8").unwrap();
+        assert_eq!(scheme, "basic");
+        assert_eq!(params["realm"], "registry");
+        assert_eq!(params["charset"], "UTF-8");
+
+        let (scheme,params) = parse_challenge("Bearer").unwrap();
+        assert_eq!(scheme, "bearer");
+        assert!(params.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_challenges() {
+        assert!(parse_challenge(r#"Bearer realm="unterminated"#).is_none());
+        assert!(parse_challenge("Bearer realm").is_none());
+    }
+}
@@ -0,0 +1,289 @@
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use log::{error, warn};
+use oci_spec::image::ImageIndex;
+use regex::Regex;
+use reqwest::{header, Body, Method};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{common::now, digest::{Algorithm, Digest}, error::{Result, StorageError}, manifest::ManifestRefs, policy::glob_to_regex, reference::Reference, registry::{found, RegistryClient}, storage::find_manifest, Storage};
+
+/// Seconds before the first retry of a failed replication, doubled with every
+/// further failure up to the configured maximum.
+const RETRY_BACKOFF: u64 = 30;
+
+/// Downstream registry receiving every push to the repositories matching one of
+/// its repository globs.
+pub struct ReplicationTarget {
+    registry: RegistryClient,
+    repositories: Vec<Regex>
+}
+
+#[derive(Debug,Clone)]
+pub struct ReplicationOptions {
+    /// Failed replications are given up after this many attempts, 0 retries forever.
+    pub max_attempts: u32,
+    /// Upper bound in seconds of the delay between two attempts.
+    pub max_backoff: u64,
+}
+
+/// Entry of the replication queue kept in the cache under `_replication/`. It
+/// names what to bring in sync rather than what changed: a tag is pushed as it
+/// is when the task runs or deleted downstream when it is gone, so retries and
+/// tasks overtaking each other still leave the downstream with the latest state.
+#[derive(Serialize,Deserialize)]
+struct ReplicationTask {
+    /// Changes whenever the task is queued again, see `settle_replication`.
+    id: String,
+    target: String,
+    repo: String,
+    reference: String,
+    attempts: u32,
+    not_before: i64
+}
+
+impl ReplicationTarget {
+
+    /// `repositories` are glob patterns where `*` matches any run of characters.
+    pub fn new(registry: RegistryClient,repositories: &[String]) -> std::result::Result<ReplicationTarget,regex::Error> {
+
+        let repositories = repositories.iter().map(|r| glob_to_regex(r)).collect::<std::result::Result<Vec<Regex>,regex::Error>>()?;
+
+        Ok(ReplicationTarget{registry,repositories})
+    }
+
+    fn url(&self) -> &str {
+        self.registry.url()
+    }
+
+    fn matches(&self,repo: &str) -> bool {
+        self.repositories.iter().any(|r| r.is_match(repo))
+    }
+
+    async fn has_blob(&self,repo: &str,digest: &Digest) -> Result<bool> {
+
+        let resp = self.registry.send(repo, self.registry.request(Method::HEAD, repo, &format!("blobs/{digest}"))).await?;
+
+        Ok(found(resp)?.is_some())
+    }
+
+    async fn has_manifest(&self,repo: &str,digest: &Digest) -> Result<bool> {
+
+        let resp = self.registry.send(repo, self.registry.request(Method::HEAD, repo, &format!("manifests/{digest}"))).await?;
+
+        Ok(found(resp)?.is_some())
+    }
+
+    /// Monolithic upload, the session is opened first so the streamed `PUT` is
+    /// sent with the token obtained for it.
+    async fn push_blob(&self,repo: &str,digest: &Digest,size: u64,body: Body) -> Result<()> {
+
+        let resp = self.registry.send(repo, self.registry.request(Method::POST, repo, "blobs/uploads/")).await?.error_for_status()?;
+        let location = resp.headers().get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(StorageError::Upstream(format!("upload of {digest} was answered without a location")))?;
+
+        let req = self.registry.request_location(Method::PUT, location)
+            .query(&[("digest",digest.to_string())])
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, size)
+            .body(body);
+        self.registry.send(repo, req).await?.error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn put_manifest(&self,repo: &str,reference: &Reference,media_type: &str,data: Vec<u8>) -> Result<()> {
+
+        let req = self.registry.request(Method::PUT, repo, &format!("manifests/{reference}"))
+            .header(header::CONTENT_TYPE, media_type)
+            .body(data);
+        self.registry.send(repo, req).await?.error_for_status()?;
+
+        Ok(())
+    }
+
+    /// A tag the downstream does not know counts as deleted.
+    async fn delete_manifest(&self,repo: &str,reference: &Reference) -> Result<()> {
+
+        let resp = self.registry.send(repo, self.registry.request(Method::DELETE, repo, &format!("manifests/{reference}"))).await?;
+        found(resp)?;
+
+        Ok(())
+    }
+}
+
+impl Storage {
+
+/// Queues `reference` of `repo` for every target replicating the repository.
+/// Failures are logged, they never fail the push or delete that caused them.
+pub(super) async fn queue_replication(&self,repo:&String,reference:&Reference) {
+
+    let targets: Vec<&ReplicationTarget> = self.replication.iter().filter(|t| t.matches(repo)).collect();
+    if targets.is_empty() {
+        return;
+    }
+
+    let _lock = self.replication_lock.lock().await;
+    for target in targets {
+        let task = ReplicationTask{id: Uuid::new_v4().to_string(),target: target.url().to_string(),repo: repo.clone(),reference: reference.to_string(),attempts: 0,not_before: now()};
+        if let Err(e) = self.write_replication_task(&task).await {
+            error!("queueing replication of {repo}:{reference} to {} failed: {e}",task.target);
+        }
+    }
+    self.replication_queued.notify_one();
+}
+
+/// Waits until something is queued for replication or `timeout` passed.
+pub async fn wait_for_replication(&self,timeout: Duration) {
+    let _ = tokio::time::timeout(timeout, self.replication_queued.notified()).await;
+}
+
+/// Runs every queued replication that is due and returns how many succeeded.
+/// Failed ones are retried with exponential backoff.
+pub async fn replicate_pending(&self,opts: &ReplicationOptions) -> Result<usize> {
+
+    let entries = match self.cache.list("_replication/").await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut replicated = 0;
+    for entry in entries.iter().filter(|e| e.name().ends_with(".json")) {
+        let task = match self.read_replication_task(entry.path()).await {
+            Ok(task) => task,
+            Err(e) => {
+                warn!("skipping unreadable replication task {}: {e}",entry.path());
+                continue;
+            },
+        };
+        if task.not_before > now() {
+            continue;
+        }
+
+        let target = self.replication.iter().find(|t| t.url() == task.target);
+        let reference = task.reference.parse::<Reference>().ok();
+        let (Some(target),Some(reference)) = (target,reference) else {
+            warn!("dropping replication of {}:{} to {}, the target is no longer configured",task.repo,task.reference,task.target);
+            self.settle_replication(task, Ok(()), opts).await?;
+            continue;
+        };
+
+        let result = self.replicate(target, &task.repo, &reference).await;
+        if result.is_ok() {
+            replicated += 1;
+        }
+        self.settle_replication(task, result, opts).await?;
+    }
+
+    Ok(replicated)
+}
+
+/// Brings `reference` on the target in line with this registry, a manifest
+/// is pushed after the blobs and child manifests it references.
+async fn replicate(&self,target: &ReplicationTarget,repo:&String,reference:&Reference) -> Result<()> {
+
+    let index = self.get_image_index(repo).await?;
+
+    let Some(descriptor) = find_manifest(&index, reference) else {
+        return match reference {
+            Reference::Tag(_) => target.delete_manifest(repo, reference).await,
+            // only tag deletes are propagated
+            Reference::Digest(_) => Ok(()),
+        };
+    };
+
+    let digest: Digest = descriptor.digest().parse()?;
+    let media_type = descriptor.media_type().to_string();
+
+    self.replicate_manifest(target, repo, &index, &digest, Some(media_type), reference).await
+}
+
+/// `media_type` is the one of the descriptor pointing at the manifest, the
+/// manifest's own `mediaType` stands in when the descriptor has none.
+async fn replicate_manifest(&self,target: &ReplicationTarget,repo:&String,index: &ImageIndex,digest:&Digest,media_type: Option<String>,reference:&Reference) -> Result<()> {
+
+    let data = self.get_blobs(repo, digest).await?;
+    let refs: ManifestRefs = serde_json::from_slice(&data)?;
+    let media_type = media_type.or(refs.media_type.clone()).unwrap_or_default();
+
+    for blob in refs.blob_refs() {
+        let blob: Digest = blob.digest.parse()?;
+        if target.has_blob(repo, &blob).await? {
+            continue;
+        }
+        let size = self.stat_blob(repo, &blob).await?;
+        let stream = self.get_blob_stream(repo, &blob, 0..size).await?;
+        target.push_blob(repo, &blob, size, Body::wrap_stream(stream)).await?;
+    }
+
+    for child in refs.manifests.iter() {
+        let child_digest: Digest = child.digest.parse()?;
+        if target.has_manifest(repo, &child_digest).await? {
+            continue;
+        }
+        let child_ref = Reference::Digest(child_digest.clone());
+        let child_type = child.media_type.clone()
+            .or_else(|| find_manifest(index, &child_ref).map(|d| d.media_type().to_string()));
+        Box::pin(self.replicate_manifest(target, repo, index, &child_digest, child_type, &child_ref)).await?;
+    }
+
+    target.put_manifest(repo, reference, &media_type, data).await
+}
+
+/// Removes a finished task or schedules its next attempt. A task queued again
+/// while it ran is left alone, the new one replicates the newer state.
+async fn settle_replication(&self,mut task: ReplicationTask,result: Result<()>,opts: &ReplicationOptions) -> Result<()> {
+
+    let _lock = self.replication_lock.lock().await;
+    let path = Self::create_replication_path(&replication_key(&task));
+
+    if !self.read_replication_task(&path).await.is_ok_and(|t| t.id == task.id) {
+        return Ok(());
+    }
+
+    let Err(e) = result else {
+        self.cache.delete(&path).await?;
+        return Ok(());
+    };
+
+    task.attempts += 1;
+    if opts.max_attempts > 0 && task.attempts >= opts.max_attempts {
+        error!("giving up replicating {}:{} to {} after {} attempts: {e}",task.repo,task.reference,task.target,task.attempts);
+        self.cache.delete(&path).await?;
+        return Ok(());
+    }
+
+    let backoff = RETRY_BACKOFF.saturating_mul(1 << (task.attempts - 1).min(16)).min(opts.max_backoff);
+    warn!("replicating {}:{} to {} failed, retrying in {backoff}s: {e}",task.repo,task.reference,task.target);
+    task.not_before = now() + backoff as i64;
+
+    self.write_replication_task(&task).await
+}
+
+async fn read_replication_task(&self,path: &str) -> Result<ReplicationTask> {
+
+    let data = self.cache.read(path).await?;
+
+    Ok(serde_json::from_slice(&data.to_vec())?)
+}
+
+async fn write_replication_task(&self,task: &ReplicationTask) -> Result<()> {
+
+    let data = Bytes::from(serde_json::to_vec(task)?);
+    self.cache.write(&Self::create_replication_path(&replication_key(task)), data).await?;
+
+    Ok(())
+}
+
+}
+
+/// Tasks for the same reference and target share a file, queueing one again
+/// replaces the pending one.
+fn replication_key(task: &ReplicationTask) -> String {
+    let name = format!("{}\n{}\n{}",task.target,task.repo,task.reference);
+    Digest::from_content(Algorithm::default(), name.as_bytes()).encoded().to_string()
+}
@@ -1,299 +1,1623 @@
-use std::{collections::HashMap, path::{Path, PathBuf}};
+use std::{collections::{HashMap, HashSet}, fmt::Display, ops::Range, pin::Pin, sync::{Arc, Mutex, PoisonError}};
 
 use actix_web::web::{Buf, Bytes};
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use log::warn;
 use oci_spec::image::{Descriptor, ImageIndex, ImageIndexBuilder, ImageManifest, MediaType};
-use opendal::{Buffer, Operator};
-use sha2::{Digest, Sha256};
+use opendal::{Buffer, FuturesBytesStream, Operator, Writer};
+use tokio::sync::{Notify, OwnedMutexGuard, RwLock, RwLockWriteGuard};
 use uuid::Uuid;
 
-use super::{common::Tags, error::StorageError};
+use super::{common::{now, Tags, UploadSession}, digest::{Algorithm, Digest, Digester}, error::StorageError, manifest::ManifestRefs, policy::TagPolicy, reference::Reference, replication::ReplicationTarget, upstream::Upstream};
 use crate::storage::error::Result;
 
+/// Blobs are kept once in a content-addressable pool under `blobs/<digest>`.
+/// A repository only owns links (`repo/<name>/_links/<digest>`) into that pool,
+/// so the same layer pushed to many repositories is stored a single time.
 pub struct Storage {
-    primary: Operator,
-    cache: Operator
+    pub(super) primary: Operator,
+    pub(super) cache: Operator,
+    /// Digests linked while a garbage collection runs, `None` when none does.
+    gc_linked: Mutex<Option<HashSet<Digest>>>,
+    /// Held for reading from the moment a blob is looked up in the pool until it
+    /// is linked, the sweep of the pool holds it for writing.
+    sweep_lock: RwLock<()>,
+    /// Serialize read-modify-write cycles of a repository's index, one lock per
+    /// repository so changes to different repositories do not wait on each other.
+    index_locks: Mutex<HashMap<String,Arc<tokio::sync::Mutex<()>>>>,
+    /// Hashes of the data staged by the upload sessions, keyed by the staging path.
+    upload_hashers: Mutex<HashMap<String,UploadHasher>>,
+    /// Serialize appends to an upload session and its completion, keyed by the
+    /// staging path.
+    upload_locks: Mutex<HashMap<String,Arc<tokio::sync::Mutex<()>>>>,
+    tag_policy: TagPolicy,
+    /// Registry mirrored as a pull-through cache, manifests and blobs missing here
+    /// are fetched from it.
+    upstream: Option<Upstream>,
+    /// Downstream registries pushes are replicated to.
+    pub(super) replication: Vec<ReplicationTarget>,
+    /// Serializes changes to the replication queue.
+    pub(super) replication_lock: tokio::sync::Mutex<()>,
+    /// Wakes the replication worker when something was queued.
+    pub(super) replication_queued: Notify
 }
 
-pub fn new(primary: Operator,cache: Operator) -> Storage {Storage{primary,cache}}
+/// Size of the parts exchanged with the primary storage while a blob is streamed
+/// in or out of the pool, this bounds the memory used per transfer.
+const WRITE_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Tag of the referrers tag schema, `<alg>-<encoded>` of the subject cut to the
+/// longest tag allowed.
+pub fn referrers_tag(subject: &Digest) -> String {
+    let mut tag = format!("{}-{}",subject.algorithm().as_str(),subject.encoded());
+    tag.truncate(128);
+    tag
+}
+
+/// Docker image manifest schema 2 and manifest list, stored and served as pushed.
+pub const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+pub const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// Annotation carrying the tag of a manifest listed in a repository's `index.json`.
+pub const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+pub fn ref_name(d: &Descriptor) -> Option<&String> {
+    d.annotations().as_ref().and_then(|a| a.get(REF_NAME))
+}
+
+pub(super) fn find_manifest<'a>(index: &'a ImageIndex,reference: &Reference) -> Option<&'a Descriptor> {
+    match reference {
+        Reference::Tag(tag) => index.manifests().iter().find(|m| ref_name(m) == Some(tag)),
+        Reference::Digest(digest) => {
+            let digest = digest.to_string();
+            index.manifests().iter().find(|m| m.digest().eq(&digest))
+        },
+    }
+}
+
+/// Locks the entry `key` of a map of locks, locks nobody holds or waits for are
+/// dropped on the way.
+async fn lock_entry(locks: &Mutex<HashMap<String,Arc<tokio::sync::Mutex<()>>>>,key: &str) -> OwnedMutexGuard<()> {
+
+    let lock = {
+        let mut locks = locks.lock().unwrap_or_else(PoisonError::into_inner);
+        locks.retain(|k,l| k == key || Arc::strong_count(l) > 1);
+        locks.entry(key.to_string()).or_default().clone()
+    };
+
+    lock.lock_owned().await
+}
+
+/// Referrers of `subject` to write once the repository index is written, `None`
+/// when the last one was removed.
+pub(super) struct PendingReferrers {
+    subject: Digest,
+    data: Option<Vec<u8>>
+}
+
+/// Digest of the data an upload session staged so far, fed as the chunks arrive
+/// so completing the session does not read the staged data once more. It is only
+/// kept in memory, after a restart the staged data is hashed on completion.
+struct UploadHasher {
+    staged: u64,
+    hasher: Digester
+}
+
+/// A blob on its way from the upstream to a client, staged as an upload session.
+struct UpstreamBlob<S> {
+    body: Pin<Box<S>>,
+    writer: Option<Writer>,
+    store: Arc<Storage>,
+    repo: String,
+    digest: Digest,
+    location: String,
+    size: u64
+}
+
+impl<S> UpstreamBlob<S> {
+
+    /// Staging is given up on a failed write, the client still gets the content.
+    async fn stage(&mut self,chunk: &Bytes) {
+        if let Some(writer) = self.writer.as_mut() {
+            if let Err(e) = writer.write(chunk.clone()).await {
+                warn!("staging {}@{} from the upstream failed: {e}",self.repo,self.digest);
+                self.writer = None;
+            }
+        }
+    }
+
+    async fn abort(&mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            let _ = writer.abort().await;
+        }
+        let _ = self.store.remove_upload(&self.repo, &self.location).await;
+    }
+
+    async fn commit(&mut self) {
+        let Some(mut writer) = self.writer.take() else {
+            return;
+        };
+
+        let result = match writer.close().await {
+            Ok(_) => self.store.delete_blob_upload(&self.repo, &self.digest, &self.location, Some(self.size)).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!("caching {}@{} from the upstream failed: {e}",self.repo,self.digest);
+        }
+    }
+}
+
+pub fn new(primary: Operator,cache: Operator) -> Storage {
+    Storage{primary,cache,gc_linked: Mutex::new(None),sweep_lock: RwLock::new(()),index_locks: Mutex::new(HashMap::new()),
+        upload_hashers: Mutex::new(HashMap::new()),upload_locks: Mutex::new(HashMap::new()),tag_policy: TagPolicy::default(),upstream: None,
+        replication: Vec::new(),replication_lock: tokio::sync::Mutex::new(()),replication_queued: Notify::new()}
+}
 
 impl Storage {
-    
-pub async fn get_manifest(&self,repo:&String,tag:&String) -> Result<Vec<u8>>{
-            if tag.starts_with("sha256:") {
-               let data =  self.get_blobs(repo, tag).await?;
-               return  Ok(data);
-
-            }else {
-                let img_index = self.get_image_index(repo).await?;
-                for  m in img_index.manifests().into_iter(){
-
-                    let a = m.annotations().as_ref();
-                    if a.is_some() {
-                        let a = a.unwrap();   
-                        let ta = a.get("org.opencontainers.image.ref.name");
-                        
-                        if ta.is_some() && ta.unwrap().eq(tag){
-                            let data = self.get_blobs(repo, m.digest()).await?;
-                            return  Ok(data);
-                        }
-                    }
-                   
-                }
+
+/// Protects the tags matched by `policy` from being moved or deleted.
+pub fn with_tag_policy(mut self,policy: TagPolicy) -> Storage {
+    self.tag_policy = policy;
+    self
+}
+
+/// Turns the registry into a pull-through cache of `upstream`.
+pub fn with_upstream(mut self,upstream: Upstream) -> Storage {
+    self.upstream = Some(upstream);
+    self
+}
+
+/// Replicates pushes and tag deletes to `targets`.
+pub fn with_replication(mut self,targets: Vec<ReplicationTarget>) -> Storage {
+    self.replication = targets;
+    self
+}
+
+/// Descriptor recorded in the index when the manifest was pushed, holding its
+/// digest, size and media type.
+pub async fn stat_manifest(&self,repo:&String,reference:&Reference) -> Result<Descriptor> {
+
+    // an unreachable upstream leaves whatever was cached before to be served
+    if let Some(upstream) = &self.upstream {
+        if let Err(e) = self.sync_upstream_manifest(upstream, repo, reference).await {
+            warn!("fetching {repo}:{reference} from the upstream failed: {e}");
+        }
+    }
+
+    let img_index = self.get_image_index(repo).await?;
+
+    find_manifest(&img_index, reference).cloned().ok_or(StorageError::ContenNotFound)
+}
+
+/// Brings a manifest of the pull-through cache up to date. A digest is fetched
+/// once, a tag again after it was served for longer than the tag ttl, unless the
+/// upstream still points it at the same manifest.
+async fn sync_upstream_manifest(&self,upstream: &Upstream,repo:&String,reference:&Reference) -> Result<()> {
+
+    let index = self.get_image_index(repo).await?;
+    let local = find_manifest(&index, reference).map(|d| d.digest().to_string());
+
+    match reference {
+        Reference::Digest(_) if local.is_some() => return Ok(()),
+        Reference::Tag(tag) if local.is_some() => {
+            if self.is_upstream_tag_fresh(repo, tag, upstream.tag_ttl()).await {
+                return Ok(());
             }
-           Err(StorageError::ContenNotFound)
+            if upstream.head_manifest(repo, reference).await?.is_some_and(|d| local.as_ref() == Some(&d.to_string())) {
+                return self.touch_upstream_tag(repo, tag).await;
+            }
+        },
+        _ => {},
+    }
+
+    let Some(manifest) = upstream.get_manifest(repo, reference).await? else {
+        return Ok(());
+    };
+
+    if manifest.digest.as_ref().is_some_and(|d| !Digest::from_content(d.algorithm(), &manifest.data).eq(d)) {
+        return Err(StorageError::DigestInvalid);
     }
 
-pub async fn get_blobs(&self,repo:&String,digest:&String) -> Result<Vec<u8>> {
-    
-    let blob_path = Self::create_blob_path(repo, digest);
-    let p = blob_path.to_str().unwrap();
-    let d = self.primary.read(p).await?;
+    let size = manifest.data.len();
+    self.store_manifest(repo, reference, manifest.data, size, &manifest.media_type, false).await?;
+
+    if let Reference::Tag(tag) = reference {
+        self.touch_upstream_tag(repo, tag).await?;
+    }
+
+    Ok(())
+}
+
+async fn is_upstream_tag_fresh(&self,repo:&String,tag:&String,ttl: u64) -> bool {
+
+    let cutoff = now() - ttl as i64;
 
-    return  Ok(d.to_vec());
+    self.cache.stat(&Self::create_upstream_tag_path(repo, tag)).await
+        .is_ok_and(|meta| meta.last_modified().is_some_and(|t| t.timestamp() > cutoff))
+}
+
+async fn touch_upstream_tag(&self,repo:&String,tag:&String) -> Result<()> {
+
+    self.cache.write(&Self::create_upstream_tag_path(repo, tag), Buffer::new()).await?;
+
+    Ok(())
+}
+
+/// Size of a blob the upstream holds, for answering HEAD without fetching it.
+pub async fn stat_upstream_blob(&self,repo:&str,digest:&Digest) -> Result<u64> {
+
+    let upstream = self.upstream.as_ref().ok_or(StorageError::ContenNotFound)?;
+
+    upstream.head_blob(repo, digest).await?.ok_or(StorageError::ContenNotFound)
+}
+
+/// Streams a blob missing here from the upstream. The content is staged like an
+/// upload while it passes through and committed once the client received all of
+/// it, a transfer that is cut short leaves a session for the reaper.
+pub async fn fetch_upstream_blob(self: Arc<Self>,repo:&String,digest:&Digest) -> Result<(u64,impl Stream<Item = Result<Bytes>> + use<>)> {
+
+    let upstream = self.upstream.as_ref().ok_or(StorageError::ContenNotFound)?;
+    let (size,body) = upstream.get_blob(repo, digest).await?.ok_or(StorageError::ContenNotFound)?;
+
+    let location = self.new_blob_upload(repo).await?;
+    let writer = self.cache.writer(&Self::create_upload_path(repo, &location)).await?;
+
+    let tee = UpstreamBlob{body: Box::pin(body),writer: Some(writer),store: self.clone(),repo: repo.clone(),digest: digest.clone(),location,size};
+
+    let stream = futures_util::stream::unfold(Some(tee), |tee| async move {
+        let mut tee = tee?;
+        match tee.body.next().await {
+            Some(Ok(chunk)) => {
+                tee.stage(&chunk).await;
+                Some((Ok(chunk),Some(tee)))
+            },
+            Some(Err(e)) => {
+                tee.abort().await;
+                Some((Err(e.into()),None))
+            },
+            None => {
+                tee.commit().await;
+                None
+            },
+        }
+    });
+
+    Ok((size,stream))
+}
+
+pub async fn get_manifest(&self,repo:&String,reference:&Reference) -> Result<(Descriptor,Vec<u8>)>{
+
+    let descriptor = self.stat_manifest(repo, reference).await?;
+    let data = self.get_blobs(repo, &descriptor.digest().parse()?).await?;
+
+    Ok((descriptor,data))
+}
+
+pub async fn get_blobs(&self,repo:&String,digest:&Digest) -> Result<Vec<u8>> {
+
+    if !self.is_blob_linked(repo, digest).await? {
+        return Err(StorageError::ContenNotFound);
+    }
+
+    let d = self.primary.read(&Self::create_blob_path(digest)).await?;
+
+    Ok(d.to_vec())
  }
 
+/// Returns the size of a blob linked into `repo` without reading its content.
+pub async fn stat_blob(&self,repo:&String,digest:&Digest) -> Result<u64> {
+
+    if !self.is_blob_linked(repo, digest).await? {
+        return Err(StorageError::ContenNotFound);
+    }
+
+    let meta = self.primary.stat(&Self::create_blob_path(digest)).await?;
+
+    Ok(meta.content_length())
+}
+
+/// Opens a stream over `range` of a blob linked into `repo`.
+pub async fn get_blob_stream(&self,repo:&String,digest:&Digest,range: Range<u64>) -> Result<FuturesBytesStream> {
+
+    if !self.is_blob_linked(repo, digest).await? {
+        return Err(StorageError::ContenNotFound);
+    }
+
+    let stream = self.primary.reader_with(&Self::create_blob_path(digest))
+        .chunk(WRITE_CHUNK_SIZE)
+        .await?
+        .into_bytes_stream(range)
+        .await?;
+
+    Ok(stream)
+}
+
 pub async fn get_tags(&self,repo:&String) -> Result<Tags>{
-    
-    let tag_path = Path::new("repo").join(repo).join("tags.json");
-    
-    match  self.cache.read(&tag_path.to_str().unwrap()).await {
-        Ok(data) => {
-            
-            let tags:Tags = serde_json::from_reader(data.reader())?;
-            return Ok(tags);   
-        },
-        Err(_) => {
 
-        let t = Tags{name: repo.to_string(),tags:Vec::new()};
-        let data = serde_json::to_vec(&t)?;
-        self.cache.write(&tag_path.to_str().unwrap(), data).await?;
+    let tag_path = Self::create_tags_path(repo);
+
+    match  self.cache.read(&tag_path).await {
+        Ok(data) => {
 
-        return  Ok(t);
+            let tags:Tags = serde_json::from_reader(data.reader())?;
+            Ok(tags)
         },
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(Tags{name: repo.to_string(),tags:Vec::new()}),
+        Err(e) => Err(e.into()),
     }
 }
 
 pub async fn update_tags(&self,repo:&String,tag:Tags)-> Result<()>{
 
-    let tag_path = Path::new("repo").join(repo).join("tags.json");
     let data = serde_json::to_vec(&tag)?;
 
-    self.cache.write(&tag_path.to_str().unwrap(), data).await?;
+    self.cache.write(&Self::create_tags_path(repo), data).await?;
 
     Ok(())
 
 }
-pub async fn write_manifest(&self,repo:&String,tag:&String,data: Bytes,size: usize,media_type: &String) -> Result<(String,String)> {
+/// Stores a manifest pushed by tag or by digest. A manifest pushed by digest is
+/// hashed with that digest's algorithm and rejected when the content does not match.
+/// Pushing a tag moves it, the manifest it pointed at before stays in the index
+/// as an untagged manifest that is still addressable by digest. Immutable tags
+/// only accept a re-push of the manifest they already point at.
+pub async fn write_manifest(&self,repo:&String,reference:&Reference,data: Bytes,size: usize,media_type: &String) -> Result<(Digest,String)> {
 
-    let digest = Self::digest_from_content(&data);
-    let mut tagannotaions = HashMap::<String,String>::new();
-    let mut tags = self.get_tags(repo).await?;
-   
+    let stored = self.store_manifest(repo, reference, data, size, media_type, true).await?;
+    self.queue_replication(repo, reference).await;
 
-    match tag.starts_with("sha256:") {
-        true => {
-            let path = Self::create_blob_path(repo, tag);
-            self.primary.write(&path.to_str().unwrap(), data.to_vec()).await?;
-        },
-        false => {
-            tagannotaions.insert("org.opencontainers.image.ref.name".to_owned(), tag.clone());
-            tags.tags.push(tag.to_string());
-            self.update_tags(repo, tags).await?;
-            
-            let path = Self::create_blob_path(repo, &digest);
-            self.primary.write(&path.to_str().unwrap(), data.to_vec()).await?;
+    Ok(stored)
+}
 
+/// Stores a manifest either pushed by a client or fetched from the upstream. The
+/// blobs of a fetched manifest are only fetched when pulled, so its references
+/// are not verified, and the tag policies do not apply to the upstream's tags.
+async fn store_manifest(&self,repo:&String,reference:&Reference,data: Bytes,size: usize,media_type: &String,pushed: bool) -> Result<(Digest,String)> {
 
+    let digest = match reference {
+        Reference::Digest(d) => {
+            if !Digest::from_content(d.algorithm(), &data).eq(d) {
+                return Err(StorageError::DigestInvalid);
+            }
+            d.clone()
         },
+        Reference::Tag(_) => Digest::from_content(Algorithm::default(), &data),
     };
-    let mut img_index = self.get_image_index(repo).await.unwrap();
 
-    let mut descriptors = img_index.manifests().to_owned();
     let mut subject_digest = String::new();
 
-    let mut descriptor: Descriptor = Descriptor::new(MediaType::from(media_type.as_str()),size as i64 , digest.clone());
+    let mut descriptor: Descriptor = Descriptor::new(MediaType::from(media_type.as_str()),size as i64 , digest.to_string());
+
+    // Docker schema2 has neither subjects nor annotations, it is only checked here
+    if pushed {
+        self.verify_manifest_refs(repo, &data, media_type).await?;
+    }
 
    if String::from(MediaType::ImageManifest).eq(media_type) {
-    let m = ImageManifest::from_reader(data.clone().reader())?;
+    let m: ImageManifest = serde_json::from_slice(&data).map_err(|e| StorageError::ManifestInvalid(e.to_string()))?;
      subject_digest = match m.subject() {
         Some(s) =>s.digest().to_string(),
         None => "".to_string(),
     };
     descriptor.set_annotations(m.annotations().clone());
+    descriptor.set_artifact_type(m.artifact_type().clone().or(Some(m.config().media_type().clone())));
    }else if String::from(MediaType::ImageIndex).eq(media_type){
-    let i= ImageIndex::from_reader(data.reader())?; 
+    let i: ImageIndex = serde_json::from_slice(&data).map_err(|e| StorageError::ManifestInvalid(e.to_string()))?;
     subject_digest = match i.subject() {
         Some(s) =>s.digest().to_string(),
         None => "".to_string(),
     };
     descriptor.set_annotations(i.annotations().clone());
+    descriptor.set_artifact_type(i.artifact_type().clone());
 
    }
 
-   let mut an: HashMap<String, String> ;
-   if descriptor.annotations().is_some() {
-    an = descriptor.annotations().to_owned().unwrap();
-    an.extend(tagannotaions.into_iter());
-   }else {
-    an = tagannotaions;
-   }
+    let subject = (!subject_digest.is_empty()).then(|| subject_digest.parse::<Digest>()).transpose()?;
+    let referrer = descriptor.clone();
+
+    let _lock = self.lock_index(repo).await;
+    let mut img_index = self.get_image_index(repo).await?;
+    let mut descriptors = img_index.manifests().to_owned();
+
+    // a refused push must not leave anything behind
+    if let Reference::Tag(tag) = reference {
+        let moved = descriptors.iter().any(|d| ref_name(d) == Some(tag) && d.digest().ne(descriptor.digest()));
+        if pushed && moved && self.tag_policy.is_immutable(repo, tag) {
+            return Err(StorageError::Denied(format!("tag {tag} is immutable")));
+        }
+    }
+
+    self.write_blob(repo, &digest, data.to_vec()).await?;
+
+    let referrers = match &subject {
+        Some(subject) => Some(self.update_referrers(repo, &mut descriptors, subject, &referrer, true).await?),
+        None => None,
+    };
+
+    match reference {
+        Reference::Tag(tag) => Self::move_tag(&mut descriptors, tag, descriptor),
+        Reference::Digest(_) => {
+            if !descriptors.iter().any(|d| d.digest().eq(descriptor.digest())) {
+                descriptors.push(descriptor);
+            }
+        },
+    }
 
-   descriptor.set_annotations(Some(an));
-   descriptors.push(descriptor);
    img_index.set_manifests(descriptors);
     self.update_image_index(repo, img_index).await?;
 
-    return Ok((digest,subject_digest));
+    if let Some(referrers) = referrers {
+        self.write_referrers(repo, referrers).await?;
+    }
+
+    Ok((digest,subject_digest))
+
+}
+
+/// Checks that the blobs and child manifests a pushed manifest points at are
+/// stored in `repo` with the sizes their descriptors give. The content type must
+/// be given and agree with the manifest's own `mediaType`, so the checks can not
+/// be skipped by leaving it out. Subjects may be pushed later and are not checked.
+async fn verify_manifest_refs(&self,repo:&String,data:&[u8],media_type:&String) -> Result<()> {
+
+    if media_type.is_empty() {
+        return Err(StorageError::ManifestInvalid("manifest pushed without a content type".to_string()));
+    }
+
+    let refs: ManifestRefs = serde_json::from_slice(data).map_err(|e| StorageError::ManifestInvalid(e.to_string()))?;
+
+    if let Some(m) = refs.media_type.as_ref().filter(|m| m.ne(&media_type)) {
+        return Err(StorageError::ManifestInvalid(format!("mediaType {m} does not match the content type {media_type}")));
+    }
+    let is_manifest = String::from(MediaType::ImageManifest).eq(media_type) || DOCKER_MANIFEST.eq(media_type);
+    if is_manifest && refs.config.is_none() {
+        return Err(StorageError::ManifestInvalid("manifest has no config".to_string()));
+    }
+
+    let mut missing = Vec::new();
+    for r in refs.blob_refs().chain(refs.manifests.iter()) {
+        let digest: Digest = r.digest.parse().map_err(|_| StorageError::ManifestInvalid(format!("invalid digest {}",r.digest)))?;
+        match self.stat_blob(repo, &digest).await {
+            Ok(size) if size == r.size => {},
+            Ok(size) => return Err(StorageError::ManifestInvalid(format!("{digest} has a size of {size}, not {}",r.size))),
+            Err(StorageError::ContenNotFound) => missing.push(digest.to_string()),
+            Err(e) => return Err(e),
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(StorageError::ManifestBlobUnknown(missing));
+    }
+
+    Ok(())
+}
+
+/// Adds or removes `referrer` in the referrers of `subject`. They are kept as an
+/// image index under `_referrers/<subject>` that is also tagged `<alg>-<encoded>`
+/// for clients without support for the referrers API, the tag is removed together
+/// with the last referrer. The tag is updated in `descriptors`, the returned
+/// referrers are written with `write_referrers` once the index is.
+pub(super) async fn update_referrers(&self,repo:&String,descriptors: &mut Vec<Descriptor>,subject:&Digest,referrer:&Descriptor,add: bool) -> Result<PendingReferrers> {
+
+    let mut index = self.read_referrers(repo, subject).await?;
+    let mut referrers = index.manifests().to_owned();
+
+    referrers.retain(|r| r.digest().ne(referrer.digest()));
+    if add {
+        referrers.push(referrer.clone());
+    }
+
+    let tag = referrers_tag(subject);
+    let current = descriptors.iter().find(|d| ref_name(d) == Some(&tag)).cloned();
+
+    let pending = if referrers.is_empty() {
+        Self::untag(descriptors, &tag);
+        PendingReferrers{subject: subject.clone(),data: None}
+    } else {
+        index.set_manifests(referrers);
+        let data = index.to_string()?.into_bytes();
+
+        let digest = Digest::from_content(Algorithm::default(), &data);
+        let descriptor = Descriptor::new(MediaType::ImageIndex, data.len() as i64, digest.to_string());
+        self.write_blob(repo, &digest, data.clone()).await?;
+        Self::move_tag(descriptors, &tag, descriptor);
+        PendingReferrers{subject: subject.clone(),data: Some(data)}
+    };
+
+    // the previous fallback index was only ever served through the tag
+    if let Some(old) = current {
+        descriptors.retain(|d| !(d.digest().eq(old.digest()) && ref_name(d).is_none()));
+    }
+
+    Ok(pending)
+}
+
+pub(super) async fn write_referrers(&self,repo:&String,pending: PendingReferrers) -> Result<()> {
+
+    let path = Self::create_referrers_path(repo, &pending.subject);
+    match pending.data {
+        Some(data) => self.primary.write(&path, data).await?,
+        None => self.primary.delete(&path).await?,
+    };
+
+    Ok(())
+}
+
+/// Points `tag` at the manifest described by `descriptor`. Each tag is carried by
+/// exactly one descriptor, a manifest that loses its last tag is kept untagged
+/// and an untagged entry of the newly tagged manifest is replaced.
+fn move_tag(descriptors: &mut Vec<Descriptor>,tag: &String,mut descriptor: Descriptor) {
+
+    Self::untag(descriptors, tag);
+    descriptors.retain(|d| !(d.digest().eq(descriptor.digest()) && ref_name(d).is_none()));
+
+    let mut an = descriptor.annotations().clone().unwrap_or_default();
+    an.insert(REF_NAME.to_string(), tag.clone());
+    descriptor.set_annotations(Some(an));
+    descriptors.push(descriptor);
+}
+
+/// Removes `tag` from the descriptors, a manifest left without any entry is kept
+/// as an untagged one.
+fn untag(descriptors: &mut Vec<Descriptor>,tag: &String) {
 
+    let previous: Vec<Descriptor> = descriptors.iter().filter(|d| ref_name(d) == Some(tag)).cloned().collect();
+    descriptors.retain(|d| ref_name(d) != Some(tag));
+
+    for mut old in previous {
+        if !descriptors.iter().any(|d| d.digest().eq(old.digest())) {
+            let mut an = old.annotations().clone().unwrap_or_default();
+            an.remove(REF_NAME);
+            old.set_annotations((!an.is_empty()).then_some(an));
+            descriptors.push(old);
+        }
+    }
+}
+
+/// Links a blob that `from` already holds into `repo`. Returns `false` when the
+/// source repository does not have the blob, so the caller can fall back to a
+/// regular upload session.
+pub async fn mount_blob(&self,repo:&String,from:&String,digest:&Digest) -> Result<bool> {
+
+    if !self.is_blob_linked(from, digest).await? {
+        return Ok(false);
+    }
+
+    let _guard = self.sweep_lock.read().await;
+    if !self.primary.exists(&Self::create_blob_path(digest)).await? {
+        return Ok(false);
+    }
+
+    self.link_blob(repo, digest).await?;
+
+    Ok(true)
 }
 
 pub async fn new_blob_upload(&self,repo:&String) -> Result<String> {
     let upload_uuid = Uuid::new_v4();
 
-    let f_path = Path::new("repo").join(repo).join(".cache").join(upload_uuid.to_string());
+    let location = upload_uuid.to_string();
 
-    self.cache.write(&f_path.to_str().unwrap(),Buffer::new()).await?;
+    self.cache.write(&Self::create_upload_path(repo, &location),Buffer::new()).await?;
 
-    Ok(upload_uuid.to_string())
+    let time = now();
+    self.write_upload_session(repo, &location, &UploadSession{created_at: time,updated_at: time}).await?;
+
+    Ok(location)
 }
 
-pub async fn update_blob_upload(&self,repo:&String,location:&String,from:u64,data: Vec<u8>) -> Result<()> {
+/// Appends `data` to an upload session whose staged data ends at `from`. A chunk
+/// of a known `length` is received apart and only appended once it arrived in
+/// full, a longer or shorter one is refused with `SizeInvalid` and leaves the
+/// session as it was.
+pub async fn update_blob_upload<S,E>(&self,repo:&String,location:&String,from:u64,length: Option<u64>,data: S) -> Result<u64>
+where S: Stream<Item = std::result::Result<Bytes,E>>, E: Display {
+
+    let f_path = Self::create_upload_path(repo, location);
+    let _lock = lock_entry(&self.upload_locks, &f_path).await;
 
-    let f_path =Path::new("repo").join(repo)
-                              .join(".cache")
-                              .join(location); 
-    let meta = self.cache.stat(&f_path.to_str().unwrap()).await?;
+    let meta = self.cache.stat(&f_path).await?;
     if meta.content_length() != from {
         return Err(StorageError::RangeIsNotStatisfied);
     }
-    self.cache.write_with(&f_path.to_str().unwrap(), data).append(true).await?;
-    Ok(())
+
+    let Some(length) = length else {
+        return self.streamed_blob_upload(repo, location, data).await;
+    };
+
+    let part = Self::create_upload_part_path(repo, location);
+    let received = self.receive_upload_part(&part, length, data).await;
+    if received.as_ref().is_ok_and(|n| *n == length) {
+        let staged = self.cache.reader(&part).await?.into_bytes_stream(..).await?;
+        self.streamed_blob_upload(repo, location, staged).await?;
+        self.cache.delete(&part).await?;
+        return Ok(length);
+    }
+
+    self.cache.delete(&part).await?;
+    received.and(Err(StorageError::SizeInvalid))
+}
+
+/// Writes a chunk to `part`, stopping as soon as it turns out longer than `length`.
+async fn receive_upload_part<S,E>(&self,part:&str,length: u64,data: S) -> Result<u64>
+where S: Stream<Item = std::result::Result<Bytes,E>>, E: Display {
+
+    let mut writer = self.cache.writer(part).await?;
+    let mut data = std::pin::pin!(data);
+    let mut received = 0;
+
+    while let Some(chunk) = data.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                writer.close().await?;
+                return Err(StorageError::Payload(e.to_string()));
+            },
+        };
+        received += chunk.len() as u64;
+        if received > length {
+            break;
+        }
+        writer.write(chunk).await?;
+    }
+    writer.close().await?;
+
+    Ok(received)
 }
 
 pub async fn get_blob_upload(&self,repo:&String,location:&String) -> Result<usize> {
-    
-    let f_path =Path::new("repo").join(repo)
-                              .join(".cache")
-                              .join(location); 
-    
-   let meta = self.cache.stat(&f_path.to_str().unwrap()).await?;
+
+   let meta = self.cache.stat(&Self::create_upload_path(repo, location)).await?;
    let  n =  meta.content_length();
    Ok(n as usize)
 }
 
-pub async fn streamed_blob_upload(&self,repo:&String,location:&String,data: Vec<u8>) -> Result<()> {
-    let f_path =Path::new("repo").join(repo)
-                              .join(".cache")
-                              .join(location); 
-    
-   self.cache.write_with(&f_path.to_str().unwrap(), data).append(true).await?;
-   
-   Ok(())
+/// Appends the request body to the staged upload chunk by chunk and returns the
+/// number of bytes written. Whatever arrived before a broken body is kept, so the
+/// client can resume from the offset reported by the upload status.
+async fn streamed_blob_upload<S,E>(&self,repo:&String,location:&String,data: S) -> Result<u64>
+where S: Stream<Item = std::result::Result<Bytes,E>>, E: Display {
+
+   let f_path = Self::create_upload_path(repo, location);
+
+   // appending would silently recreate a session that was completed or reaped
+   let staged = self.cache.stat(&f_path).await?.content_length();
+   let mut hasher = self.take_upload_hasher(&f_path, staged);
+
+   let mut writer = self.cache.writer_with(&f_path).append(true).await?;
+   let mut data = std::pin::pin!(data);
+   let mut written = 0;
+
+   while let Some(chunk) = data.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                writer.close().await?;
+                self.keep_upload_hasher(&f_path, staged + written, hasher);
+                return Err(StorageError::Payload(e.to_string()));
+            },
+        };
+        written += chunk.len() as u64;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&chunk);
+        }
+        writer.write(chunk).await?;
+   }
+   writer.close().await?;
+   self.keep_upload_hasher(&f_path, staged + written, hasher);
+
+   self.touch_upload_session(repo, location).await?;
+
+   Ok(written)
+}
+
+/// Hasher of the data staged at `path`, a new one while nothing is staged yet.
+/// `None` when the data staged was not hashed on the way in.
+fn take_upload_hasher(&self,path:&str,staged: u64) -> Option<Digester> {
+
+    let mut hashers = self.upload_hashers.lock().unwrap_or_else(PoisonError::into_inner);
+    match hashers.remove(path) {
+        Some(h) if h.staged == staged => Some(h.hasher),
+        _ if staged == 0 => Some(Algorithm::default().hasher()),
+        _ => None,
+    }
+}
+
+fn keep_upload_hasher(&self,path:&str,staged: u64,hasher: Option<Digester>) {
+
+    if let Some(hasher) = hasher {
+        self.upload_hashers.lock().unwrap_or_else(PoisonError::into_inner).insert(path.to_string(), UploadHasher{staged,hasher});
+    }
+}
+
+/// Completes an upload session: the staged content is checked against the digest
+/// (and size, when the client announced one) it is claimed to have, using the
+/// hash taken while it was staged when there is one. On a mismatch the session
+/// is discarded and nothing is stored.
+pub async fn delete_blob_upload(&self,repo:&String,digest:&Digest,location:&String,size: Option<u64>) -> Result<()>{
+    let cached_blob = Self::create_upload_path(repo, location);
+    let _lock = lock_entry(&self.upload_locks, &cached_blob).await;
+
+    let meta = self.cache.stat(&cached_blob).await?;
+
+    if size.is_some_and(|s| s != meta.content_length()) {
+        self.remove_upload(repo, location).await?;
+        return Err(StorageError::SizeInvalid);
+    }
+
+    let hashed = self.take_upload_hasher(&cached_blob, meta.content_length())
+        .filter(|h| h.algorithm() == digest.algorithm());
+    let hasher = match hashed {
+        Some(hasher) => hasher,
+        None => {
+            let mut hasher = digest.algorithm().hasher();
+            let mut staged = self.cache.reader(&cached_blob).await?.into_bytes_stream(..).await?;
+            while let Some(chunk) = staged.try_next().await? {
+                hasher.update(&chunk);
+            }
+            hasher
+        },
+    };
+
+    if !hasher.finalize().eq(digest) {
+        self.remove_upload(repo, location).await?;
+        return Err(StorageError::DigestInvalid);
+    }
+
+    let _guard = self.sweep_lock.read().await;
+    if !self.primary.exists(&Self::create_blob_path(digest)).await? {
+        let staged = self.cache.reader(&cached_blob).await?.into_bytes_stream(..).await?;
+        self.write_blob_stream(digest, staged).await?;
+    }
+    self.link_blob(repo, digest).await?;
+
+    self.remove_upload(repo, location).await
+}
+
+/// Discards an upload session and its staged data.
+pub async fn cancel_blob_upload(&self,repo:&String,location:&String) -> Result<()> {
+
+    self.cache.stat(&Self::create_upload_path(repo, location)).await?;
+
+    self.remove_upload(repo, location).await
 }
-pub async fn delete_blob_upload(&self,repo:&String,digest:&String,location:&String) -> Result<()>{
-    let cached_blob = Path::new("repo").join(repo).join(".cache").join(location);
 
-    let data = self.cache.read(&cached_blob.to_str().unwrap()).await?;
-    
-    let blob_path = Self::create_blob_path(repo, digest);
-   
-    self.primary.write(&blob_path.to_str().unwrap(), data).await?;
-    self.cache.delete(&cached_blob.to_str().unwrap()).await?;
+/// Removes upload sessions that saw no activity for `ttl` seconds and returns how
+/// many were removed. Staged data without session state is judged by its own
+/// modification time.
+pub async fn reap_upload_sessions(&self,ttl: u64) -> Result<usize> {
+
+    let cutoff = now() - ttl as i64;
+    let mut reaped = 0;
+
+    let entries = match self.cache.list_with("repo/").recursive(true).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+
+    for entry in entries {
+        let path = entry.path();
+        if !path.contains("/.cache/") || path.ends_with('/') || path.ends_with(".json") {
+            continue;
+        }
+
+        let last_activity = match self.cache.read(&format!("{path}.json")).await {
+            Ok(data) => serde_json::from_reader::<_,UploadSession>(data.reader()).map_or(0, |s| s.updated_at),
+            Err(_) => self.cache.stat(path).await?.last_modified().map_or(0, |t| t.timestamp()),
+        };
+
+        if last_activity < cutoff {
+            self.upload_hashers.lock().unwrap_or_else(PoisonError::into_inner).remove(path);
+            self.cache.delete(path).await?;
+            self.cache.delete(&format!("{path}.json")).await?;
+            reaped += 1;
+        }
+    }
+
+    Ok(reaped)
+}
+
+async fn write_upload_session(&self,repo:&String,location:&String,session: &UploadSession) -> Result<()> {
+
+    self.cache.write(&Self::create_upload_session_path(repo, location), serde_json::to_vec(session)?).await?;
 
     Ok(())
 }
 
-pub async fn delete_manifest(&self,repo:&String,digest:&String) -> Result<()>{
+async fn touch_upload_session(&self,repo:&String,location:&String) -> Result<()> {
+
+    let time = now();
+    let mut session = match self.cache.read(&Self::create_upload_session_path(repo, location)).await {
+        Ok(data) => serde_json::from_reader(data.reader())?,
+        Err(_) => UploadSession{created_at: time,updated_at: time},
+    };
+    session.updated_at = time;
+
+    self.write_upload_session(repo, location, &session).await
+}
+
+async fn remove_upload(&self,repo:&String,location:&String) -> Result<()> {
 
-    let mut index = self.get_image_index(repo).await.unwrap();
+    let path = Self::create_upload_path(repo, location);
+    self.upload_hashers.lock().unwrap_or_else(PoisonError::into_inner).remove(&path);
+    self.cache.delete(&path).await?;
+    self.cache.delete(&Self::create_upload_part_path(repo, location)).await?;
+    self.cache.delete(&Self::create_upload_session_path(repo, location)).await?;
+
+    Ok(())
+}
+
+/// Removes `tag` from the repository, the manifest it pointed at stays
+/// addressable by digest together with its other tags.
+pub async fn delete_tag(&self,repo:&String,tag:&String) -> Result<()>{
+
+    let lock = self.lock_index(repo).await;
+    let mut index = self.get_image_index(repo).await?;
+    let mut descriptors = index.manifests().to_owned();
+
+    if !descriptors.iter().any(|d| ref_name(d) == Some(tag)) {
+        return Err(StorageError::ContenNotFound);
+    }
+    if self.tag_policy.is_immutable(repo, tag) {
+        return Err(StorageError::Denied(format!("tag {tag} is immutable")));
+    }
+
+    Self::untag(&mut descriptors, tag);
+    index.set_manifests(descriptors);
+    self.update_image_index(repo, index).await?;
+    drop(lock);
+
+    self.queue_replication(repo, &Reference::Tag(tag.clone())).await;
+
+    Ok(())
+}
+
+/// Removes a manifest and every tag pointing at it.
+pub async fn delete_manifest(&self,repo:&String,digest:&Digest) -> Result<()>{
+
+    let lock = self.lock_index(repo).await;
+    let mut index = self.get_image_index(repo).await?;
     let mut new_manifests = index.manifests().clone();
-    let mut repo_tags = self.get_tags(repo).await?;
-   
-    for m in new_manifests.clone().iter() {
-        let an = m.annotations().clone().or(Some(HashMap::new())).unwrap();
-        match an.contains_key("org.opencontainers.image.ref.name")  {
-            true => {
-               let tag =  an.get("org.opencontainers.image.ref.name").unwrap();                              
-                repo_tags.tags.retain(|t: &String| {
-                    !t.eq(tag)
-                });
-            },
-            false =>{},
-        };
-    }     
 
+    let digest_str = digest.to_string();
+
+    if !new_manifests.iter().any(|d| d.digest().eq(&digest_str)) {
+        return Err(StorageError::ContenNotFound);
+    }
+
+    if let Some(tag) = new_manifests.iter()
+        .filter(|d| d.digest().eq(&digest_str))
+        .filter_map(ref_name)
+        .find(|t| self.tag_policy.is_immutable(repo, t)) {
+        return Err(StorageError::Denied(format!("manifest is tagged with immutable tag {tag}")));
+    }
+
+    let referrer = new_manifests.iter().find(|d| d.digest().eq(&digest_str)).cloned();
+    let tags: Vec<String> = new_manifests.iter()
+        .filter(|d| d.digest().eq(&digest_str))
+        .filter_map(ref_name)
+        .cloned()
+        .collect();
     new_manifests.retain(| d| {
-        !d.digest().eq(digest)
+        !d.digest().eq(&digest_str)
     });
 
+    let referrers = match (referrer,self.read_subject(repo, digest).await) {
+        (Some(referrer),Some(subject)) => Some(self.update_referrers(repo, &mut new_manifests, &subject, &referrer, false).await?),
+        _ => None,
+    };
+
     index.set_manifests(new_manifests);
-   
-    self.update_tags(repo, repo_tags).await?;
-    self.update_image_index(repo, index).await.unwrap();
+    self.update_image_index(repo, index).await?;
+    if let Some(referrers) = referrers {
+        self.write_referrers(repo, referrers).await?;
+    }
+    drop(lock);
+
+    self.unlink_blob(repo, digest).await?;
+
+    for tag in tags {
+        self.queue_replication(repo, &Reference::Tag(tag)).await;
+    }
+
+    Ok(())
+}
 
-    let blob_path = Self::create_blob_path(repo, digest);
+/// Subject of a stored manifest, `None` when it has none or can not be read.
+async fn read_subject(&self,repo:&String,digest:&Digest) -> Option<Digest> {
 
-    self.primary.delete(&blob_path.to_str().unwrap()).await?;
+    let data = self.get_blobs(repo, digest).await.ok()?;
+    let manifest: serde_json::Value = serde_json::from_slice(&data).ok()?;
+
+    manifest.get("subject")?.get("digest")?.as_str()?.parse().ok()
+}
+
+pub async fn delete_blob(&self,repo:&String,digest:&Digest) -> Result<()>{
+
+    self.unlink_blob(repo, digest).await
+
+}
+
+/// Lists every repository name under `repo/`. A directory is a repository once it
+/// holds an `index.json` or blob links, nested names are found by descending into
+/// the remaining sub directories. The `blobs/` of a repository kept under the old
+/// layout is only looked into when it is a repository itself.
+pub async fn list_repositories(&self) -> Result<Vec<String>> {
+
+    let mut repositories = Vec::new();
+    let mut pending = vec![String::from("repo/")];
+
+    while let Some(dir) = pending.pop() {
+        let mut is_repository = false;
+        let mut legacy_blobs = None;
+
+        for entry in self.primary.list(&dir).await? {
+            let path = entry.path();
+            if path == dir {
+                continue;
+            }
+
+            match entry.name() {
+                "index.json" | "_links/" => is_repository = true,
+                "blobs/" => legacy_blobs = Some(path.to_string()),
+                name if name.ends_with('/') && !name.starts_with(['_','.']) => pending.push(path.to_string()),
+                _ => {},
+            }
+        }
+
+        if let Some(blobs) = legacy_blobs {
+            if !is_repository || self.primary.exists(&format!("{blobs}index.json")).await? || self.primary.exists(&format!("{blobs}_links/")).await? {
+                pending.push(blobs);
+            }
+        }
+
+        if is_repository {
+            let name = dir.trim_start_matches("repo/").trim_end_matches('/');
+            repositories.push(name.to_string());
+        }
+    }
+
+    repositories.sort();
+
+    Ok(repositories)
+}
+
+/// The repositories a manifest was pushed to, as the catalog lists them. They are
+/// kept under `_repositories/` as a manifest is first written to each, so the
+/// catalog does not walk the whole tree. Repositories written before that list
+/// existed are added to it once.
+pub async fn list_catalog(&self) -> Result<Vec<String>> {
+
+    let marker = Self::create_catalog_marker_path();
+    if !self.primary.exists(&marker).await? {
+        for repo in self.list_repositories().await? {
+            if self.repository_exists(&repo).await? {
+                self.add_to_catalog(&repo).await?;
+            }
+        }
+        self.primary.write(&marker, Buffer::new()).await?;
+    }
+
+    let mut repositories: Vec<String> = self.primary.list_with("_repositories/").recursive(true).await?
+        .iter()
+        .filter_map(|entry| entry.path().strip_prefix("_repositories/")?.strip_suffix("/_repository"))
+        .map(str::to_string)
+        .collect();
+    repositories.sort();
+
+    Ok(repositories)
+}
+
+async fn add_to_catalog(&self,repo:&String) -> Result<()> {
+
+    let path = Self::create_catalog_path(repo);
+    if !self.primary.exists(&path).await? {
+        self.primary.write(&path, Buffer::new()).await?;
+    }
 
     Ok(())
 }
 
-pub async fn delete_blob(&self,repo:&String,digest:&String) -> Result<()>{
+/// The manifests pushed with `subject` as their subject, an empty index when
+/// there are none.
+pub async fn get_referrers(&self,repo:&String,subject:&Digest) -> Result<ImageIndex> {
+
+    self.backfill_referrers(repo).await?;
+    self.read_referrers(repo, subject).await
+}
+
+/// Referrers pushed before they were kept under `_referrers/` are only known from
+/// the subjects of the manifests in the index. They are collected once per
+/// repository, a marker records that it happened. The manifests are read without
+/// the index lock, it is only taken to write what was found.
+async fn backfill_referrers(&self,repo:&String) -> Result<()> {
+
+    let marker = Self::create_referrers_marker_path(repo);
+    if self.primary.exists(&marker).await? {
+        return Ok(());
+    }
+
+    let index = self.get_image_index(repo).await?;
+    if index.manifests().is_empty() {
+        return Ok(());
+    }
+
+    let mut found: HashMap<Digest,Vec<Descriptor>> = HashMap::new();
+    let mut seen = HashSet::new();
+    for descriptor in index.manifests() {
+        if !seen.insert(descriptor.digest().clone()) {
+            continue;
+        }
+        if let Some((subject,referrer)) = self.read_referrer(repo, descriptor).await {
+            found.entry(subject).or_default().push(referrer);
+        }
+    }
+
+    let _lock = self.lock_index(repo).await;
+    if self.primary.exists(&marker).await? {
+        return Ok(());
+    }
+
+    // Manifests deleted in the meantime already left their subject's referrers,
+    // manifests pushed in the meantime were added to them when they were stored.
+    let current: HashSet<String> = self.get_image_index(repo).await?
+        .manifests().iter().map(|d| d.digest().to_string()).collect();
+
+    for (subject,mut referrers) in found {
+        referrers.retain(|r| current.contains(r.digest()));
+        if referrers.is_empty() {
+            continue;
+        }
+        let mut referrers_index = self.read_referrers(repo, &subject).await?;
+        let mut manifests = referrers_index.manifests().to_owned();
+        for referrer in referrers {
+            if !manifests.iter().any(|m| m.digest().eq(referrer.digest())) {
+                manifests.push(referrer);
+            }
+        }
+        referrers_index.set_manifests(manifests);
+        self.primary.write(&Self::create_referrers_path(repo, &subject), referrers_index.to_string()?.into_bytes()).await?;
+    }
 
-    let blob_path = Self::create_blob_path(repo, digest);
-    self.primary.delete(&blob_path.to_str().unwrap()).await?;
+    self.primary.write(&marker, Buffer::new()).await?;
 
     Ok(())
+}
+
+/// Subject and referrers entry of a manifest listed in the index, described the
+/// way `store_manifest` describes a referrer.
+async fn read_referrer(&self,repo:&String,descriptor: &Descriptor) -> Option<(Digest,Descriptor)> {
 
-}   
+    let digest: Digest = descriptor.digest().parse().ok()?;
+    let data = self.get_blobs(repo, &digest).await.ok()?;
+    let manifest: serde_json::Value = serde_json::from_slice(&data).ok()?;
+    let subject = manifest.get("subject")?.get("digest")?.as_str()?.parse().ok()?;
+
+    let mut referrer = Descriptor::new(descriptor.media_type().clone(), descriptor.size(), descriptor.digest().clone());
+    let artifact_type = manifest.get("artifactType")
+        .or(manifest.get("config").and_then(|c| c.get("mediaType")))
+        .and_then(|a| a.as_str());
+    referrer.set_artifact_type(artifact_type.map(MediaType::from));
+    referrer.set_annotations(manifest.get("annotations").and_then(|a| serde_json::from_value(a.clone()).ok()));
+
+    Some((subject,referrer))
+}
+
+async fn read_referrers(&self,repo:&String,subject:&Digest) -> Result<ImageIndex> {
+
+    match self.primary.read(&Self::create_referrers_path(repo, subject)).await {
+        Ok(data) => Ok(ImageIndex::from_reader(data.reader())?),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(ImageIndexBuilder::default()
+            .schema_version(2_u32)
+            .media_type(MediaType::ImageIndex)
+            .manifests(Vec::new())
+            .build()?),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A repository exists once a manifest was pushed to it.
+pub async fn repository_exists(&self,repo:&String) -> Result<bool> {
+
+    Ok(self.primary.exists(&Self::create_index_path(repo)).await?)
+}
 
 pub async fn get_image_index(&self,repo:&String) -> Result<ImageIndex> {
-  
-  let index_path =  Path::new("repo").join(repo).join("index.json");
 
-    match self.primary.read(&index_path.to_str().unwrap()).await {
+  let index_path =  Self::create_index_path(repo);
+
+    match self.primary.read(&index_path).await {
         Ok(data) =>{
-            let index = ImageIndex::from_reader(data.reader()).expect("error in reading index");
-            return Ok(index);
+            let index = ImageIndex::from_reader(data.reader())?;
+            Ok(index)
         },
-        Err(_) => {
+        // written with the first manifest, see `update_image_index`
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => {
             let index = ImageIndexBuilder::default()
-            .schema_version(2 as u32)
+            .schema_version(2_u32)
             .media_type("application/vnd.oci.image.index.v1+json")
             .manifests(Vec::new())
             .build()?;
-           let data =  index.to_string()?;   
-           self.primary.write(&index_path.to_str().unwrap(), data.into_bytes().to_vec()).await?;
-           return  Ok(index);
+           Ok(index)
         },
-    }  
+        Err(e) => Err(e.into()),
+    }
+
+}
 
+
+/// Locks the index of `repo`.
+pub(super) async fn lock_index(&self,repo:&str) -> OwnedMutexGuard<()> {
+
+    lock_entry(&self.index_locks, repo).await
 }
 
+/// Writes `index.json` and the `tags.json` derived from it. Callers hold the lock
+/// of `lock_index` from reading the index until it is written back.
+pub(super) async fn  update_image_index(&self,repo:&String,index:ImageIndex) -> Result<()>{
 
-async fn  update_image_index(&self,repo:&String,index:ImageIndex) -> Result<()>{
-    
-    let index_path =  Path::new("repo").join(repo).join("index.json");
+    let tags = Tags{name: repo.to_string(),tags: index.manifests().iter().filter_map(ref_name).cloned().collect()};
 
     let data =  index.to_string()?;
-    self.primary.write_with(&index_path.to_str().unwrap(), data.into_bytes().to_vec()).await?;
+    self.primary.write_with(&Self::create_index_path(repo), data.into_bytes().to_vec()).await?;
+    self.update_tags(repo, tags).await?;
+    self.add_to_catalog(repo).await?;
 
     Ok(())
 }
 
-fn create_blob_path(repo:&String,digest:&String) -> PathBuf {
+/// Stores `data` in the shared blob pool unless that digest is already present,
+/// then links it into `repo`.
+async fn write_blob(&self,repo:&String,digest:&Digest,data: impl Into<Buffer>) -> Result<()> {
+
+    let blob_path = Self::create_blob_path(digest);
+
+    let _guard = self.sweep_lock.read().await;
+    if !self.primary.exists(&blob_path).await? {
+        self.primary.write(&blob_path, data).await?;
+    }
+
+    self.link_blob(repo, digest).await
+}
+
+/// Streams content into the shared blob pool. A failed transfer is aborted so no
+/// partial object is left under `digest`.
+async fn write_blob_stream<S>(&self,digest:&Digest,data: S) -> Result<()>
+where S: Stream<Item = std::io::Result<Bytes>> {
+
+    let mut writer = self.primary.writer_with(&Self::create_blob_path(digest)).chunk(WRITE_CHUNK_SIZE).await?;
+    let mut data = std::pin::pin!(data);
+
+    while let Some(chunk) = data.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                let _ = writer.abort().await;
+                return Err(e.into());
+            },
+        };
+        if let Err(e) = writer.write(chunk).await {
+            let _ = writer.abort().await;
+            return Err(e.into());
+        }
+    }
+    writer.close().await?;
+
+    Ok(())
+}
+
+/// Callers hold `sweep_lock` for reading from the time they found the blob in the pool.
+async fn link_blob(&self,repo:&String,digest:&Digest) -> Result<()> {
+
+    self.primary.write(&Self::create_link_path(repo, digest), digest.to_string()).await?;
+
+    if let Ok(mut linked) = self.gc_linked.lock() {
+        if let Some(linked) = linked.as_mut() {
+            linked.insert(digest.clone());
+        }
+    }
+
+    Ok(())
+}
+
+pub(super) async fn unlink_blob(&self,repo:&String,digest:&Digest) -> Result<()> {
+
+    if !self.is_blob_linked(repo, digest).await? {
+        return Err(StorageError::ContenNotFound);
+    }
+    self.primary.delete(&Self::create_link_path(repo, digest)).await?;
+
+    Ok(())
+}
+
+/// Lists the digests linked into `repo` with the unix time each link was last written.
+pub(super) async fn list_links(&self,repo:&String) -> Result<Vec<(Digest,i64)>> {
+
+    let dir = format!("repo/{repo}/_links/");
+    let entries = match self.primary.list(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut links = Vec::new();
+    for entry in entries {
+        let Ok(digest) = entry.name().parse::<Digest>() else {
+            continue;
+        };
+        let modified = self.primary.stat(entry.path()).await?.last_modified().map_or(0, |t| t.timestamp());
+        links.push((digest,modified));
+    }
+
+    Ok(links)
+}
+
+/// Modification time of the link of `digest` in `repo`, `None` when there is none.
+pub(super) async fn stat_link(&self,repo:&String,digest:&Digest) -> Result<Option<i64>> {
+
+    match self.primary.stat(&Self::create_link_path(repo, digest)).await {
+        Ok(meta) => Ok(Some(meta.last_modified().map_or(0, |t| t.timestamp()))),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(super) fn begin_gc(&self) {
+
+    if let Ok(mut linked) = self.gc_linked.lock() {
+        *linked = Some(HashSet::new());
+    }
+}
+
+pub(super) fn end_gc(&self) {
+
+    if let Ok(mut linked) = self.gc_linked.lock() {
+        *linked = None;
+    }
+}
+
+/// Blocks new links into the pool and returns the digests linked since `begin_gc`.
+pub(super) async fn lock_sweep(&self) -> (RwLockWriteGuard<'_,()>,HashSet<Digest>) {
+
+    let guard = self.sweep_lock.write().await;
+    let recent = self.gc_linked.lock().ok().and_then(|l| l.clone()).unwrap_or_default();
+
+    (guard,recent)
+}
+
+/// Callers must not hold `sweep_lock`, a blob still kept under the old layout is
+/// adopted into the pool on the way.
+pub(super) async fn is_blob_linked(&self,repo:&String,digest:&Digest) -> Result<bool> {
+
+    if self.primary.exists(&Self::create_link_path(repo, digest)).await? {
+        return Ok(true);
+    }
+
+    self.adopt_legacy_blob(repo, digest).await
+}
+
+/// Blobs pushed before the shared pool existed are kept per repository under
+/// `repo/<name>/blobs/`. Such a blob is moved into the pool and linked into
+/// `repo`, `false` when there is none.
+async fn adopt_legacy_blob(&self,repo:&String,digest:&Digest) -> Result<bool> {
+
+    let legacy_path = Self::create_legacy_blob_path(repo, digest);
+    if !self.primary.exists(&legacy_path).await? {
+        return Ok(false);
+    }
+
+    let blob_path = Self::create_blob_path(digest);
+
+    let _guard = self.sweep_lock.read().await;
+    if !self.primary.exists(&blob_path).await? {
+        match self.primary.copy(&legacy_path, &blob_path).await {
+            Ok(()) => {},
+            // adopted by a concurrent request in the meantime
+            Err(e) if e.kind() == opendal::ErrorKind::NotFound => return Ok(self.primary.exists(&Self::create_link_path(repo, digest)).await?),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    self.link_blob(repo, digest).await?;
+    self.primary.delete(&legacy_path).await?;
+
+    Ok(true)
+}
+
+/// Adopts every blob still kept under the old layout into the pool and returns
+/// how many were moved.
+pub async fn migrate_legacy_blobs(&self) -> Result<usize> {
+
+    let mut migrated = 0;
+    for repo in self.list_repositories().await? {
+        let entries = match self.primary.list(&format!("repo/{repo}/blobs/")).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == opendal::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        for entry in entries {
+            let Ok(digest) = entry.name().parse::<Digest>() else {
+                continue;
+            };
+            if self.adopt_legacy_blob(&repo, &digest).await? {
+                migrated += 1;
+            }
+        }
+    }
+
+    Ok(migrated)
+}
+
+pub(super) fn create_blob_path(digest:&Digest) -> String {
+
+    format!("blobs/{digest}")
+}
+
+fn create_link_path(repo:&String,digest:&Digest) -> String {
+
+    format!("repo/{repo}/_links/{digest}")
+}
+
+fn create_legacy_blob_path(repo:&String,digest:&Digest) -> String {
+
+    format!("repo/{repo}/blobs/{digest}")
+}
+
+fn create_upload_path(repo:&String,location:&String) -> String {
+
+    format!("repo/{repo}/.cache/{location}")
+}
+
+fn create_upload_part_path(repo:&String,location:&String) -> String {
+
+    format!("repo/{repo}/.cache/{location}.part")
+}
+
+fn create_upload_session_path(repo:&String,location:&String) -> String {
+
+    format!("repo/{repo}/.cache/{location}.json")
+}
+
+fn create_referrers_path(repo:&String,subject:&Digest) -> String {
 
-    Path::new("repo").join(repo).join("blobs").join(digest)
+    format!("repo/{repo}/_referrers/{subject}")
 }
 
+fn create_catalog_path(repo:&String) -> String {
 
-fn digest_from_content(data: &Bytes) -> String{
-        
-    let mut hasher = Sha256::new();
-    hasher.update(data);
-    let hash = hasher.finalize();
+    format!("_repositories/{repo}/_repository")
+}
+
+fn create_catalog_marker_path() -> String {
+
+    "_repositories/_listed".to_string()
+}
+
+fn create_referrers_marker_path(repo:&String) -> String {
+
+    format!("repo/{repo}/_referrers/_backfilled")
+}
+
+pub(super) fn create_replication_path(key:&str) -> String {
 
-    format!("sha256:{:x}",hash)
+    format!("_replication/{key}.json")
 }
 
-}
\ No newline at end of file
+fn create_upstream_tag_path(repo:&String,tag:&String) -> String {
+
+    format!("repo/{repo}/_upstream/{tag}")
+}
+
+fn create_index_path(repo:&String) -> String {
+
+    format!("repo/{repo}/index.json")
+}
+
+fn create_tags_path(repo:&String) -> String {
+
+    format!("repo/{repo}/tags.json")
+}
+
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::time::Duration;
+
+    use opendal::services;
+
+    use super::*;
+    use crate::storage::policy::ImmutableTags;
+
+    /// A store on a fresh directory, the primary storage doubles as the cache.
+    pub fn temp_storage() -> Storage {
+        let root = std::env::temp_dir().join(format!("ferridock-test-{}",Uuid::new_v4()));
+        let op = Operator::new(services::Fs::default().root(&root.to_string_lossy())).unwrap().finish();
+        new(op.clone(), op)
+    }
+
+    /// Uploads `data` as a blob of `repo`.
+    pub async fn push_blob(store: &Storage,repo: &str,data: &[u8]) -> Digest {
+        let repo = repo.to_string();
+        let digest = Digest::from_content(Algorithm::default(), data);
+        let location = store.new_blob_upload(&repo).await.unwrap();
+        let body = futures_util::stream::iter([Ok::<_,StorageError>(Bytes::copy_from_slice(data))]);
+        store.update_blob_upload(&repo, &location, 0, None, body).await.unwrap();
+        store.delete_blob_upload(&repo, &digest, &location, None).await.unwrap();
+        digest
+    }
+
+    /// Pushes an image manifest with a fresh config blob, tagged when `tag` is
+    /// given and referring to `subject` when that is given.
+    pub async fn push_image(store: &Storage,repo: &str,tag: Option<&str>,subject: Option<&Digest>) -> Digest {
+        let (digest,data) = image_manifest(store, repo, subject).await;
+        let reference = match tag {
+            Some(tag) => Reference::Tag(tag.to_string()),
+            None => Reference::Digest(digest.clone()),
+        };
+        write_image(store, repo, &reference, data).await.unwrap();
+        digest
+    }
+
+    async fn write_image(store: &Storage,repo: &str,reference: &Reference,data: Bytes) -> Result<(Digest,String)> {
+        let size = data.len();
+        store.write_manifest(&repo.to_string(), reference, data, size, &String::from(MediaType::ImageManifest)).await
+    }
+
+    /// An image manifest whose fresh config blob is pushed to `repo`.
+    async fn image_manifest(store: &Storage,repo: &str,subject: Option<&Digest>) -> (Digest,Bytes) {
+        let config = Uuid::new_v4().to_string();
+        let config_digest = push_blob(store, repo, config.as_bytes()).await;
+        let mut manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": {"mediaType": "application/vnd.oci.image.config.v1+json","digest": config_digest.to_string(),"size": config.len()},
+            "layers": []
+        });
+        if let Some(subject) = subject {
+            let (_,data) = store.get_manifest(&repo.to_string(), &Reference::Digest(subject.clone())).await.unwrap();
+            manifest["subject"] = serde_json::json!({
+                "mediaType": "application/vnd.oci.image.manifest.v1+json","digest": subject.to_string(),"size": data.len()
+            });
+        }
+        let data = serde_json::to_vec(&manifest).unwrap();
+        (Digest::from_content(Algorithm::default(), &data),Bytes::from(data))
+    }
+
+    #[actix_web::test]
+    async fn locks_indexes_per_repository() {
+        let store = temp_storage();
+        let (a,b) = ("a".to_string(),"b".to_string());
+
+        let held = store.lock_index(&a).await;
+        assert!(tokio::time::timeout(Duration::from_secs(1), store.lock_index(&b)).await.is_ok());
+        assert!(tokio::time::timeout(Duration::from_millis(50), store.lock_index(&a)).await.is_err());
+
+        drop(held);
+        assert!(tokio::time::timeout(Duration::from_secs(1), store.lock_index(&a)).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn drops_idle_index_locks() {
+        let store = temp_storage();
+
+        for repo in ["a","b","c"] {
+            let _lock = store.lock_index(repo).await;
+        }
+        let _lock = store.lock_index("d").await;
+
+        assert_eq!(store.index_locks.lock().unwrap().len(), 1);
+    }
+    #[actix_web::test]
+    async fn backfills_referrers_once() {
+        let store = temp_storage();
+        let repo = "app".to_string();
+        let subject = push_image(&store, "app", Some("v1"), None).await;
+        let referrer = push_image(&store, "app", None, Some(&subject)).await;
+
+        // as left behind by a version that did not keep `_referrers/`
+        store.primary.remove_all(&format!("repo/{repo}/_referrers/")).await.unwrap();
+
+        let referrers = store.get_referrers(&repo, &subject).await.unwrap();
+        assert_eq!(referrers.manifests().len(), 1);
+        assert_eq!(referrers.manifests()[0].digest(), &referrer.to_string());
+        assert!(store.primary.exists(&Storage::create_referrers_marker_path(&repo)).await.unwrap());
+    }
+    #[actix_web::test]
+    async fn verifies_uploads_hashed_while_staged_or_not() {
+        let store = temp_storage();
+        let repo = "app".to_string();
+        let digest = Digest::from_content(Algorithm::default(), b"chunked");
+        let chunk = |data: &'static [u8]| futures_util::stream::iter([Ok::<_,StorageError>(Bytes::from_static(data))]);
+
+        let location = store.new_blob_upload(&repo).await.unwrap();
+        store.update_blob_upload(&repo, &location, 0, None, chunk(b"chun")).await.unwrap();
+        store.update_blob_upload(&repo, &location, 4, Some(3), chunk(b"ked")).await.unwrap();
+        assert!(matches!(store.delete_blob_upload(&repo, &Digest::from_content(Algorithm::default(), b"other"), &location, None).await, Err(StorageError::DigestInvalid)));
+
+        // as after a restart, the staged data is hashed on completion instead
+        let location = store.new_blob_upload(&repo).await.unwrap();
+        store.update_blob_upload(&repo, &location, 0, None, chunk(b"chun")).await.unwrap();
+        store.upload_hashers.lock().unwrap().clear();
+        store.update_blob_upload(&repo, &location, 4, Some(3), chunk(b"ked")).await.unwrap();
+        store.delete_blob_upload(&repo, &digest, &location, Some(7)).await.unwrap();
+
+        assert_eq!(store.get_blobs(&repo, &digest).await.unwrap(), b"chunked");
+        assert!(store.upload_hashers.lock().unwrap().is_empty());
+    }
+    #[actix_web::test]
+    async fn refuses_chunks_of_another_length_without_appending() {
+        let store = temp_storage();
+        let repo = "app".to_string();
+        let chunk = |data: &'static [u8]| futures_util::stream::iter([Ok::<_,StorageError>(Bytes::from_static(data))]);
+
+        let location = store.new_blob_upload(&repo).await.unwrap();
+        store.update_blob_upload(&repo, &location, 0, Some(4), chunk(b"chun")).await.unwrap();
+        assert!(matches!(store.update_blob_upload(&repo, &location, 4, Some(3), chunk(b"ke")).await, Err(StorageError::SizeInvalid)));
+        assert!(matches!(store.update_blob_upload(&repo, &location, 4, Some(3), chunk(b"kedd")).await, Err(StorageError::SizeInvalid)));
+        assert_eq!(store.get_blob_upload(&repo, &location).await.unwrap(), 4);
+
+        store.update_blob_upload(&repo, &location, 4, Some(3), chunk(b"ked")).await.unwrap();
+        store.delete_blob_upload(&repo, &Digest::from_content(Algorithm::default(), b"chunked"), &location, Some(7)).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn serializes_appends_to_a_session() {
+        let store = temp_storage();
+        let repo = "app".to_string();
+        let location = store.new_blob_upload(&repo).await.unwrap();
+        let slow = || futures_util::stream::once(async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok::<_,StorageError>(Bytes::from_static(b"chunk"))
+        });
+
+        let (a,b) = futures_util::join!(
+            store.update_blob_upload(&repo, &location, 0, Some(5), slow()),
+            store.update_blob_upload(&repo, &location, 0, Some(5), slow())
+        );
+
+        assert!(a.is_ok() != b.is_ok());
+        assert_eq!(store.get_blob_upload(&repo, &location).await.unwrap(), 5);
+    }
+    #[actix_web::test]
+    async fn lists_repositories_without_legacy_blobs() {
+        let store = temp_storage();
+        push_image(&store, "a", Some("v1"), None).await;
+        push_image(&store, "a/blobs", Some("v1"), None).await;
+        push_image(&store, "b/c", Some("v1"), None).await;
+        push_blob(&store, "d", b"link only").await;
+        store.primary.write("repo/a/blobs/sha256:0000", Buffer::new()).await.unwrap();
+
+        assert_eq!(store.list_repositories().await.unwrap(), ["a", "a/blobs", "b/c", "d"]);
+        assert_eq!(store.list_catalog().await.unwrap(), ["a", "a/blobs", "b/c"]);
+
+        // as left behind by a version that did not keep the list
+        store.primary.remove_all("_repositories/").await.unwrap();
+        assert_eq!(store.list_catalog().await.unwrap(), ["a", "a/blobs", "b/c"]);
+        push_image(&store, "e", Some("v1"), None).await;
+        assert_eq!(store.list_catalog().await.unwrap(), ["a", "a/blobs", "b/c", "e"]);
+    }
+    #[actix_web::test]
+    async fn protects_immutable_tags() {
+        let rules = vec![ImmutableTags::new("app", &["v*".to_string()], &[]).unwrap()];
+        let store = temp_storage().with_tag_policy(TagPolicy::new(rules));
+        let repo = "app".to_string();
+        let v1 = Reference::Tag("v1".to_string());
+
+        let digest = push_image(&store, "app", Some("v1"), None).await;
+        let (_,data) = store.get_manifest(&repo, &v1).await.unwrap();
+        write_image(&store, "app", &v1, Bytes::from(data)).await.unwrap();
+
+        let (_,other) = image_manifest(&store, "app", None).await;
+        assert!(matches!(write_image(&store, "app", &v1, other).await, Err(StorageError::Denied(_))));
+        assert!(matches!(store.delete_tag(&repo, &"v1".to_string()).await, Err(StorageError::Denied(_))));
+        assert!(matches!(store.delete_manifest(&repo, &digest).await, Err(StorageError::Denied(_))));
+        assert_eq!(store.stat_manifest(&repo, &v1).await.unwrap().digest(), &digest.to_string());
+
+        push_image(&store, "app", Some("latest"), None).await;
+        push_image(&store, "app", Some("latest"), None).await;
+        store.delete_tag(&repo, &"latest".to_string()).await.unwrap();
+    }
+}
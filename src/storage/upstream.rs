@@ -0,0 +1,93 @@
+use actix_web::web::Bytes;
+use futures_util::Stream;
+use reqwest::{header, Method};
+
+use super::{digest::Digest, error::{Result, StorageError}, reference::Reference, registry::{content_digest, content_length, found, RegistryClient}, storage::{DOCKER_MANIFEST, DOCKER_MANIFEST_LIST}};
+
+/// Manifest media types asked from the upstream, in order of preference.
+const MANIFEST_ACCEPT: [&str; 4] = [
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.oci.image.index.v1+json",
+    DOCKER_MANIFEST,
+    DOCKER_MANIFEST_LIST,
+];
+
+/// A manifest fetched from the upstream registry.
+pub struct UpstreamManifest {
+    pub media_type: String,
+    /// The `Docker-Content-Digest` the upstream announced, if any.
+    pub digest: Option<Digest>,
+    pub data: Bytes
+}
+
+/// The registry a pull-through cache mirrors.
+pub struct Upstream {
+    registry: RegistryClient,
+    tag_ttl: u64
+}
+
+impl Upstream {
+
+    pub fn new(url: &str,credentials: Option<(String,String)>,tag_ttl: u64) -> reqwest::Result<Upstream> {
+        Ok(Upstream{registry: RegistryClient::new(url, credentials)?,tag_ttl})
+    }
+
+    /// Seconds a tag fetched from the upstream is served before it is revalidated.
+    pub fn tag_ttl(&self) -> u64 {
+        self.tag_ttl
+    }
+
+    /// Digest the upstream currently resolves `reference` to, `None` when it does not know it.
+    pub async fn head_manifest(&self,repo: &str,reference: &Reference) -> Result<Option<Digest>> {
+
+        let Some(resp) = found(self.send_manifest(Method::HEAD, repo, reference).await?)? else {
+            return Ok(None);
+        };
+
+        Ok(content_digest(&resp))
+    }
+
+    pub async fn get_manifest(&self,repo: &str,reference: &Reference) -> Result<Option<UpstreamManifest>> {
+
+        let Some(resp) = found(self.send_manifest(Method::GET, repo, reference).await?)? else {
+            return Ok(None);
+        };
+
+        let media_type = resp.headers().get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.split(';').next().unwrap_or_default().trim().to_string())
+            .unwrap_or_default();
+        let digest = content_digest(&resp);
+        let data = resp.bytes().await?;
+
+        Ok(Some(UpstreamManifest{media_type,digest,data}))
+    }
+
+    pub async fn head_blob(&self,repo: &str,digest: &Digest) -> Result<Option<u64>> {
+
+        let resp = self.registry.send(repo, self.registry.request(Method::HEAD, repo, &format!("blobs/{digest}"))).await?;
+
+        Ok(found(resp)?.and_then(|r| content_length(&r)))
+    }
+
+    /// Size and content of a blob, the content is streamed as it arrives.
+    pub async fn get_blob(&self,repo: &str,digest: &Digest) -> Result<Option<(u64,impl Stream<Item = reqwest::Result<Bytes>>)>> {
+
+        let resp = self.registry.send(repo, self.registry.request(Method::GET, repo, &format!("blobs/{digest}"))).await?;
+        let Some(resp) = found(resp)? else {
+            return Ok(None);
+        };
+
+        let size = content_length(&resp).ok_or(StorageError::Upstream(format!("blob {digest} was sent without a length")))?;
+
+        Ok(Some((size,resp.bytes_stream())))
+    }
+
+    async fn send_manifest(&self,method: Method,repo: &str,reference: &Reference) -> Result<reqwest::Response> {
+
+        let req = self.registry.request(method, repo, &format!("manifests/{reference}"))
+            .header(header::ACCEPT, MANIFEST_ACCEPT.join(", "));
+
+        self.registry.send(repo, req).await
+    }
+}